@@ -1,10 +1,13 @@
 use crate::cli::TopLevelArgs;
+use crate::cli::{PieceRef, parse_piece_ref};
 use crate::execution_data::ExecutionData;
 use crate::full_piece::FullPiece;
 use crate::installation::Installation;
+use crate::pieces::file::LinkMode;
 use clap::ArgAction::SetTrue;
 use clap::ValueEnum;
 use color_eyre::Result;
+use color_eyre::eyre::eyre;
 use std::path::Path;
 
 #[derive(ValueEnum, Copy, Clone, Debug)]
@@ -63,9 +66,56 @@ pub struct Args {
     #[arg(short, long)]
     pub undo: Option<String>,
 
+    /// (command) Command that succeeds if the piece is already satisfied on a machine, like
+    /// `command -v duf`. The piece is then marked as done without running it. Shouldn't change anything.
+    #[arg(long)]
+    pub check: Option<String>,
+
+    /// (file) How to deploy the file: as a symlink (default), a hardlink, or a copy
+    #[arg(long, value_enum)]
+    pub mode: Option<LinkMode>,
+
+    /// (file) The file is owned by root, so manage it with root privileges (see `--escalate`).
+    /// It's deployed as a copy by default, and its permissions and ownership are restored.
+    #[arg(long)]
+    pub sudo: bool,
+
+    /// (file) Adopt a file that's already a symlink (like one from GNU Stow): copy the content it
+    /// points to into the repo, and replace the symlink. Such symlinks are replaced on other
+    /// machines too.
+    #[arg(long)]
+    pub adopt: bool,
+
+    /// (file) On machines where the file already exists, only overwrite it if it has this content.
+    /// Read from the given file, or from stdin with '-'.
+    #[arg(long, value_name = "FILE|-", conflicts_with = "expect_distro_default")]
+    pub expect_content: Option<String>,
+
+    /// (file) On machines where the file already exists, only overwrite it if it's the unmodified
+    /// version installed by its package (according to dpkg), and stop if it was customized.
+    #[arg(long)]
+    pub expect_distro_default: bool,
+
     /// Run the piece here (on this machine) immediately
     #[arg(long, short)]
     pub not_done_here: bool,
+
+    /// Only run the piece on machines with any of these tags (see `falconf machine tag`)
+    #[arg(long, value_delimiter = ',')]
+    pub only: Vec<String>,
+
+    /// Don't run the piece on machines with any of these tags (see `falconf machine tag`)
+    #[arg(long, value_delimiter = ',')]
+    pub except: Vec<String>,
+
+    /// Only run the piece on the machines that currently exist, and never on machines that are
+    /// added later. Useful for migrations, like removing an old PPA everywhere.
+    #[arg(long)]
+    pub once: bool,
+
+    /// Only run the piece after these pieces, and undo it before them. '-' is a shortcut for the last piece.
+    #[arg(long, value_delimiter = ',', value_parser = parse_piece_ref)]
+    pub after: Vec<PieceRef>,
 }
 
 #[allow(clippy::needless_pass_by_value)]
@@ -75,15 +125,29 @@ pub fn add(top_level_args: TopLevelArgs, args: Args) -> Result<()> {
     installation.pull_and_read(true)?;
     let repo = installation.repo_mut();
     let data = repo.data_mut();
+    let machines = data.machines().clone();
     let pieces = data.pieces_mut();
 
+    let after = args
+        .after
+        .iter()
+        .map(|piece_ref| {
+            let id = piece_ref.resolve(pieces)?;
+            if pieces.contains_key(&id) {
+                Ok(id)
+            } else {
+                Err(eyre!("Piece {id:08x} (from `--after`) not found"))
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+
     // Add the piece
-    let (id, piece) = FullPiece::add(&args, &execution_data)?;
+    let (id, piece) = FullPiece::add(&args, &execution_data, &machines, after)?;
     let file = piece.file().map(Path::to_path_buf);
     pieces.insert(id, piece);
 
     // Push changes
-    repo.write_and_push(file.map_or_else(Vec::new, |file| vec![file]))?;
+    repo.write_and_push_unless_dry_run(file.map_or_else(Vec::new, |file| vec![file]))?;
 
     Ok(())
 }
@@ -107,7 +171,17 @@ pub mod tests {
             _manual: (),
             value,
             undo: None,
+            check: None,
+            mode: None,
+            sudo: false,
+            adopt: false,
+            expect_content: None,
+            expect_distro_default: false,
             not_done_here: false,
+            only: vec![],
+            except: vec![],
+            once: false,
+            after: vec![],
         }
     }
 
@@ -139,6 +213,17 @@ pub mod tests {
         add_util_opts(falconf_path, piece, value, false, None)
     }
 
+    pub fn add_util_once(falconf_path: &Path, piece: Piece, value: Vec<String>) -> Result<()> {
+        let top_level_args = TopLevelArgs::new_testing(falconf_path.to_path_buf(), false);
+
+        let mut args = add_args_util(Some(piece), value, None);
+        args.once = true;
+
+        add(top_level_args, args)?;
+
+        Ok(())
+    }
+
     pub fn add_util_comment(
         falconf_path: &Path,
         piece: Piece,
@@ -3,6 +3,7 @@ use crate::cli::{PieceRef, parse_piece_ref};
 use crate::full_piece::FullPiece;
 use crate::installation::Installation;
 use crate::pieces::{NonBulkPieceEnum, PieceEnum};
+use crate::utils::read_file_or_stdin;
 use clap::ArgAction::SetTrue;
 use color_eyre::Result;
 use color_eyre::eyre::{OptionExt as _, eyre};
@@ -30,6 +31,20 @@ pub struct Args {
     /// Remove any existing undo
     #[arg(long, action=SetTrue, conflicts_with = "undo")]
     pub remove_undo: bool,
+
+    /// (file) On machines where the file already exists, only overwrite it if it has this content.
+    /// Read from the given file, or from stdin with '-'.
+    #[arg(long, value_name = "FILE|-", conflicts_with_all = ["expect_distro_default", "remove_expected_content"])]
+    pub expect_content: Option<String>,
+
+    /// (file) On machines where the file already exists, only overwrite it if it's the unmodified
+    /// version installed by its package (according to dpkg), and stop if it was customized.
+    #[arg(long, action=SetTrue, conflicts_with = "remove_expected_content")]
+    pub expect_distro_default: bool,
+
+    /// Remove any existing expected content
+    #[arg(long, action=SetTrue)]
+    pub remove_expected_content: bool,
 }
 
 #[allow(clippy::needless_pass_by_value)]
@@ -92,6 +107,45 @@ pub fn edit(top_level_args: TopLevelArgs, mut args: Args) -> Result<()> {
         }));
     }
 
+    if let Some(source) = args.expect_content.take() {
+        let content = read_file_or_stdin(&source)?;
+        operations.push(Box::new(|piece| {
+            if let PieceEnum::NonBulk(NonBulkPieceEnum::File(file)) = &mut piece.piece {
+                file.expect_content(content);
+                Ok(())
+            } else {
+                Err(eyre!(
+                    "`--expect-content` only makes sense with a file piece."
+                ))
+            }
+        }));
+    }
+    if args.expect_distro_default {
+        operations.push(Box::new(|piece| {
+            if let PieceEnum::NonBulk(NonBulkPieceEnum::File(file)) = &mut piece.piece {
+                file.expect_distro_default()
+            } else {
+                Err(eyre!(
+                    "`--expect-distro-default` only makes sense with a file piece."
+                ))
+            }
+        }));
+    }
+    if args.remove_expected_content {
+        operations.push(Box::new(|piece| {
+            if let PieceEnum::NonBulk(NonBulkPieceEnum::File(file)) = &mut piece.piece {
+                if !file.remove_expected_content() {
+                    return Err(eyre!("No expected content to remove"));
+                }
+                Ok(())
+            } else {
+                Err(eyre!(
+                    "`--remove-expected-content` only makes sense with a file piece."
+                ))
+            }
+        }));
+    }
+
     for operation in operations {
         if let Err(err) = operation(piece) {
             info!("Found error during edit; writing and pushing the changes that *were* done");
@@ -1,5 +1,5 @@
 use crate::cli::TopLevelArgs;
-use crate::installation::Installation;
+use crate::installation::{Installation, Reclaim};
 use color_eyre::Result;
 use color_eyre::eyre::WrapErr as _;
 
@@ -9,19 +9,48 @@ pub struct Args {
     #[arg(long, short)]
     new: bool,
 
+    /// Take over the identity of a machine that is already in the repo (id or a prefix of it, name, or hostname), for example after reinstalling
+    #[arg(long = "as", value_name = "MACHINE", conflicts_with = "new")]
+    as_machine: Option<String>,
+
+    /// With `--as`, forget what was done on the machine, so everything is executed again
+    #[arg(long, requires = "as_machine", conflicts_with = "keep_history")]
+    reset: bool,
+
+    /// With `--as`, keep what was done on the machine
+    #[arg(long, requires = "as_machine")]
+    keep_history: bool,
+
     /// The remote url
     remote: String,
 }
 
 #[allow(clippy::needless_pass_by_value)]
 pub fn init(top_level_args: TopLevelArgs, args: Args) -> Result<()> {
-    Installation::init(&top_level_args, &args.remote, args.new).wrap_err("Failed to init")?;
+    let reclaim = match args.as_machine {
+        Some(machine) => {
+            let reset = if args.reset || args.keep_history {
+                args.reset
+            } else {
+                top_level_args.input().confirm(
+                    "Reset what was done on this machine, so everything is executed again? Choose this if the machine was reinstalled.",
+                )?
+            };
+            Some(Reclaim { machine, reset })
+        }
+        None => None,
+    };
+    Installation::init(&top_level_args, &args.remote, args.new, reclaim)
+        .wrap_err("Failed to init")?;
     Ok(())
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::cli::add::tests::add_util;
+    use crate::cli::{add, sync};
+    use crate::full_piece::FullPiece;
     use crate::testing::{TempDirSub, TestRemote};
     use log::debug;
     use tempfile::TempDir;
@@ -34,6 +63,9 @@ pub mod tests {
 
         let args = Args {
             new,
+            as_machine: None,
+            reset: false,
+            keep_history: false,
             remote: remote.address().to_string(),
         };
 
@@ -57,5 +89,56 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_init_as() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let local_1 = init_util(&remote, true)?;
+        let local_2 = init_util(&remote, false)?;
+
+        add_util(
+            local_1.path(),
+            add::Piece::Command,
+            vec![String::from("true")],
+        )?;
+        sync::sync(
+            TopLevelArgs::new_testing(local_2.path().clone(), true),
+            sync::Args::default(),
+        )?;
+        let machine_2 =
+            *Installation::get(&TopLevelArgs::new_testing(local_2.path().clone(), true))?.machine();
+
+        let init_as = |reset: bool| -> Result<Installation> {
+            let temp = TempDir::new()?;
+            let falconf_path = temp.path().join("test_.falconf_dir");
+            let top_level_args = TopLevelArgs::new_testing(falconf_path, true);
+            let args = Args {
+                new: false,
+                as_machine: Some(machine_2.0.to_string()),
+                reset,
+                keep_history: !reset,
+                remote: remote.address().to_string(),
+            };
+            init(top_level_args.clone(), args)?;
+            Installation::get(&top_level_args)
+        };
+        let todo_len = |mut installation: Installation| {
+            let machine = *installation.machine();
+            let (pieces, machines) = installation.repo_mut().data_mut().pieces_mut_and_machines();
+            let (to_execute, to_undo) = FullPiece::get_todo(pieces, machines, &machine);
+            to_execute.len() + to_undo.len()
+        };
+
+        let installation = init_as(false)?;
+        assert_eq!(*installation.machine(), machine_2);
+        assert_eq!(installation.repo().data().machines().len(), 2);
+        assert_eq!(todo_len(installation), 0);
+
+        let installation = init_as(true)?;
+        assert_eq!(*installation.machine(), machine_2);
+        assert_eq!(todo_len(installation), 1);
+
+        Ok(())
+    }
+
     // Init is tested more extensively in sync
 }
@@ -18,7 +18,7 @@ pub fn list<W: Write>(
     let pieces = data.pieces();
 
     for (id, piece) in pieces {
-        writeln!(writer, "{}", piece.print(*id))?;
+        writeln!(writer, "{}", piece.print(*id, data.machines()))?;
     }
 
     Ok(())
@@ -67,6 +67,9 @@ pub mod tests {
                 piece: PieceRef::Last,
                 undo: Some("echo I am undoing this piece".to_string()),
                 remove_undo: false,
+                expect_content: None,
+                expect_distro_default: false,
+                remove_expected_content: false,
             },
         )?;
         // File
@@ -0,0 +1,325 @@
+use crate::cli::TopLevelArgs;
+use crate::data::Data;
+use crate::full_piece::FullPiece;
+use crate::installation::Installation;
+use crate::machine::Machine;
+use crate::utils::format_elapsed;
+use clap::Subcommand;
+use color_eyre::Result;
+use color_eyre::eyre::{OptionExt as _, eyre};
+use color_eyre::owo_colors::OwoColorize as _;
+use log::{info, warn};
+use std::io::Write;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    #[command(subcommand)]
+    command: MachineCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum MachineCommand {
+    #[command(about = "List all machines")]
+    List,
+
+    #[command(about = "Give a machine a name, shown instead of its hostname")]
+    Rename(RenameArgs),
+
+    #[command(
+        about = "Remove a machine that is no longer used, and forget everything that was done on it"
+    )]
+    Retire(RetireArgs),
+
+    #[command(
+        about = "Add or remove tags of a machine, to target pieces with `add --only` and `add --except`"
+    )]
+    Tag(TagArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct TagArgs {
+    /// The tags to add (or remove), like `laptop` or `server`
+    #[arg(required = true)]
+    tags: Vec<String>,
+
+    /// Remove the tags instead of adding them
+    #[arg(long, short)]
+    remove: bool,
+
+    /// The machine id (or a prefix of it) or hostname. Defaults to this machine.
+    #[arg(long, short)]
+    machine: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RenameArgs {
+    /// The new name
+    name: String,
+
+    /// The machine id (or a prefix of it), name, or hostname. Defaults to this machine.
+    #[arg(long, short)]
+    machine: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RetireArgs {
+    /// The machine id (or a prefix of it), name, or hostname
+    machine: String,
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub fn machine<W: Write>(top_level_args: TopLevelArgs, args: Args, writer: &mut W) -> Result<()> {
+    let mut installation = Installation::get(&top_level_args)?;
+    installation.pull_and_read(true)?;
+    let this_machine = *installation.machine();
+    let repo = installation.repo_mut();
+    let data = repo.data_mut();
+
+    match args.command {
+        MachineCommand::List => list(data, this_machine, writer)?,
+        MachineCommand::Rename(args) => rename(data, this_machine, args)?,
+        MachineCommand::Retire(args) => retire(data, this_machine, &args)?,
+        MachineCommand::Tag(args) => tag(data, this_machine, args)?,
+    }
+
+    // Push changes
+    repo.write_and_push(vec![])?;
+
+    Ok(())
+}
+
+fn resolve(data: &Data, this_machine: Machine, reference: Option<&str>) -> Result<Machine> {
+    reference.map_or(Ok(this_machine), |reference| data.find_machine(reference))
+}
+
+fn list<W: Write>(data: &mut Data, this_machine: Machine, writer: &mut W) -> Result<()> {
+    let (pieces, machines) = data.pieces_mut_and_machines();
+    for (machine, machine_data) in machines {
+        let (to_execute, to_undo) = FullPiece::get_todo(pieces, machines, machine);
+        let this_suffix = if *machine == this_machine {
+            " (this machine)"
+        } else {
+            ""
+        };
+        let hostname_suffix = if machine_data.name() == machine_data.hostname() {
+            String::new()
+        } else {
+            format!(" ({})", machine_data.hostname())
+        };
+        let tags_suffix = if machine_data.tags().is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", machine_data.tags().join(", "))
+        };
+        let last_sync = machine_data
+            .last_sync()
+            .map_or_else(|| String::from("never"), format_elapsed);
+        writeln!(
+            writer,
+            "{} {}{hostname_suffix}{this_suffix}{tags_suffix}: last sync {last_sync}, {} pending",
+            machine.0.magenta().bold(),
+            machine_data.name(),
+            to_execute.len() + to_undo.len(),
+        )?;
+    }
+    Ok(())
+}
+
+fn rename(data: &mut Data, this_machine: Machine, args: RenameArgs) -> Result<()> {
+    let machine = resolve(data, this_machine, args.machine.as_deref())?;
+    data.machines_mut()
+        .get_mut(&machine)
+        .ok_or_eyre("Machine not found")?
+        .set_name(args.name);
+    Ok(())
+}
+
+fn retire(data: &mut Data, this_machine: Machine, args: &RetireArgs) -> Result<()> {
+    let machine = data.find_machine(&args.machine)?;
+    if machine == this_machine {
+        return Err(eyre!(
+            "Cannot retire this machine. Retire it from another machine instead."
+        ));
+    }
+    data.retire(&machine)?;
+    info!("Retired machine {}", machine.0);
+    Ok(())
+}
+
+fn tag(data: &mut Data, this_machine: Machine, args: TagArgs) -> Result<()> {
+    let machine = resolve(data, this_machine, args.machine.as_deref())?;
+    let tags = data
+        .machines_mut()
+        .get_mut(&machine)
+        .ok_or_eyre("Machine not found")?
+        .tags_mut();
+
+    for tag in args.tags {
+        if args.remove {
+            if !tags.contains(&tag) {
+                warn!("Machine doesn't have tag '{tag}'");
+            }
+            tags.retain(|t| *t != tag);
+        } else if tags.contains(&tag) {
+            warn!("Machine already has tag '{tag}'");
+        } else {
+            tags.push(tag);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::cli::add::tests::add_args_util;
+    use crate::cli::init::tests::init_util;
+    use crate::cli::undo::tests::undo_util;
+    use crate::cli::{PieceRef, add::tests::add_util};
+    use crate::cli::{add, sync};
+    use crate::testing::TestRemote;
+    use std::path::Path;
+    use std::{fs, io};
+    use tempfile::TempDir;
+
+    pub fn tag_util(falconf_path: &Path, tags: Vec<String>) -> Result<()> {
+        let top_level_args = TopLevelArgs::new_testing(falconf_path.to_path_buf(), true);
+        let args = Args {
+            command: MachineCommand::Tag(TagArgs {
+                tags,
+                remove: false,
+                machine: None,
+            }),
+        };
+        machine(top_level_args, args, &mut io::sink())
+    }
+
+    #[test]
+    fn test_retire() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let local_1 = init_util(&remote, true)?;
+        let local_2 = init_util(&remote, false)?;
+
+        add_util(
+            local_1.path(),
+            add::Piece::Apt,
+            vec![String::from("cowsay")],
+        )?;
+        sync::sync(
+            TopLevelArgs::new_testing(local_2.path().clone(), true),
+            sync::Args::default(),
+        )?;
+        undo_util(local_1.path(), PieceRef::Last)?;
+
+        let top_level_args = TopLevelArgs::new_testing(local_1.path().clone(), true);
+        let mut installation = Installation::get(&top_level_args)?;
+        installation.pull_and_read(false)?;
+        let machine_2 = installation
+            .repo()
+            .data()
+            .machines()
+            .keys()
+            .copied()
+            .find(|machine| machine != installation.machine())
+            .ok_or_eyre("Cannot find the other machine")?;
+        let unused = |installation: &Installation| {
+            let data = installation.repo().data();
+            data.pieces()[0].unused(data.machines())
+        };
+        // Not undone on local 2 yet
+        assert!(!unused(&installation));
+
+        let args = Args {
+            command: MachineCommand::Retire(RetireArgs {
+                machine: machine_2.0.to_string(),
+            }),
+        };
+        machine(top_level_args, args, &mut io::sink())?;
+
+        let top_level_args = TopLevelArgs::new_testing(local_1.path().clone(), true);
+        let installation = Installation::get(&top_level_args)?;
+        assert!(
+            !installation
+                .repo()
+                .data()
+                .machines()
+                .contains_key(&machine_2)
+        );
+        // Local 2 is gone, so it doesn't need to undo it anymore
+        assert!(unused(&installation));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_target() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let test_1 = temp.path().join("test_1.txt");
+
+        let local_1 = init_util(&remote, true)?;
+        let mut args = add_args_util(
+            Some(add::Piece::Command),
+            vec![format!("touch '{}'", test_1.display())],
+            None,
+        );
+        args.only = vec![String::from("laptop")];
+        add::add(
+            TopLevelArgs::new_testing(local_1.path().clone(), false),
+            args,
+        )?;
+
+        // Not tagged, so it's not targeted
+        let local_2 = init_util(&remote, false)?;
+        sync::sync(
+            TopLevelArgs::new_testing(local_2.path().clone(), false),
+            sync::Args::default(),
+        )?;
+        assert!(!test_1.exists());
+
+        // Tagged, so now it is
+        tag_util(local_2.path(), vec![String::from("laptop")])?;
+        sync::sync(
+            TopLevelArgs::new_testing(local_2.path().clone(), false),
+            sync::Args::default(),
+        )?;
+        assert!(test_1.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_except_this_machine() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let test_1 = temp.path().join("test_1.txt");
+        let test_2 = temp.path().join("test_2.txt");
+        fs::write(&test_2, "content")?;
+
+        let local = init_util(&remote, true)?;
+        tag_util(local.path(), vec![String::from("server")])?;
+
+        let mut args = add_args_util(
+            Some(add::Piece::Command),
+            vec![format!("touch '{}'", test_1.display())],
+            None,
+        );
+        args.not_done_here = true;
+        args.except = vec![String::from("server")];
+        add::add(TopLevelArgs::new_testing(local.path().clone(), false), args)?;
+        assert!(!test_1.exists());
+
+        // Adding a file moves it into the repo, so that's refused
+        let mut args = add_args_util(
+            Some(add::Piece::File),
+            vec![test_2.display().to_string()],
+            None,
+        );
+        args.except = vec![String::from("server")];
+        assert!(add::add(TopLevelArgs::new_testing(local.path().clone(), false), args).is_err());
+        assert!(!test_2.is_symlink());
+
+        Ok(())
+    }
+}
@@ -1,12 +1,16 @@
+use crate::config::{Config, OnConflict, REPO_CONFIG_FILE};
 use crate::full_piece::FullPiece;
+use crate::installation::Installation;
+use crate::utils::Input;
 use clap::{Args, Parser, Subcommand};
 use color_eyre::Result;
-use color_eyre::eyre::OptionExt as _;
+use color_eyre::eyre::{OptionExt as _, eyre};
 use expanduser::expanduser;
 use indexmap::IndexMap;
 use log::{LevelFilter, debug};
-use std::io;
+use std::io::{self, IsTerminal as _};
 use std::path::PathBuf;
+use std::process::ExitCode;
 use std::str::FromStr as _;
 
 pub use add::Piece;
@@ -15,8 +19,11 @@ pub mod add;
 mod edit;
 pub mod init;
 mod list;
+mod machine;
+mod mv;
 mod push;
 mod remove;
+mod status;
 pub mod sync;
 pub mod undo;
 
@@ -26,6 +33,9 @@ fn parse_path(s: &str) -> Result<PathBuf> {
 
 #[derive(Parser, Debug)]
 #[command(name = "falconf", version)]
+#[command(
+    after_help = "Settings are taken from the command line, environment variables, the local config file (`--config`), and the `falconf.toml` in the root of the repo, in that order of precedence. The config files use the names of the options with underscores, for example `log_level = \"debug\"` or `confirm = false`."
+)]
 #[command(about = "TODO description")] // TODO(med): Edit the description here, in GitHub, in Cargo.toml
 pub struct Cli {
     #[command(subcommand)]
@@ -36,9 +46,9 @@ pub struct Cli {
 
 #[derive(Args, Debug, Clone)]
 pub struct TopLevelArgs {
-    /// The log level to use.
-    #[arg(long, short, default_value = "info")]
-    log_level: String,
+    /// The log level to use. [default: info]
+    #[arg(long, short, env = "FALCONF_LOG_LEVEL")]
+    pub(crate) log_level: Option<String>,
 
     /// Output debug logs. Alias for `--log-level debug`.
     #[arg(long, short)]
@@ -48,15 +58,84 @@ pub struct TopLevelArgs {
     #[arg(long, short, default_value = "~/.falconf", value_parser = parse_path, env = "FALCONF_PATH")]
     pub path: PathBuf,
 
-    // /// Don't execute any commands, and do not mark pieces as executed. WARNING: this
-    // /// is not a complete dry run, and certain commands (like push) will still retain
-    // /// their functionality. It does guarantee the data file isn't changed.
-    // #[arg(long, short)]
-    // pub dry_run: bool,
+    /// The local config file.
+    #[arg(long = "config", default_value = "~/.config/falconf/config.toml", value_parser = parse_path, env = "FALCONF_CONFIG")]
+    pub config_file: PathBuf,
+
+    /// Show what would be done, without executing anything and without writing, committing,
+    /// or pushing to the repo. Supported by `sync`, `add`, `undo`, `remove`, `mv`, and `list`.
+    #[arg(long, short)]
+    pub dry_run: bool,
+
+    /// The command to get root privileges with, for file pieces added with `--sudo`.
+    /// For example `sudo`, `doas`, or `pkexec`. [default: sudo]
+    #[arg(long, env = "FALCONF_ESCALATE")]
+    pub escalate: Option<String>,
+
+    /// The shell to run command pieces with, as `<shell> -c <command>`. [default: bash]
+    #[arg(long, env = "FALCONF_SHELL")]
+    pub shell: Option<String>,
+
+    /// The command to install and remove the packages of apt pieces with, like `apt-get` or
+    /// `sudo apt`. [default: apt]
+    #[arg(long, env = "FALCONF_APT")]
+    pub apt: Option<String>,
+
+    /// The directory in the repo that tracked files are kept in. Set it in the `falconf.toml`
+    /// of the repo, as it must be the same on every machine. Changing it doesn't move the files
+    /// that are already in the repo. [default: files]
+    #[arg(long, env = "FALCONF_FILE_DIR")]
+    pub file_dir: Option<String>,
+
+    /// The pager to show diffs with, like `less` or `delta`. Defaults to git's `core.pager`.
+    #[arg(long, env = "FALCONF_PAGER")]
+    pub pager: Option<String>,
+
+    /// The tool to show diffs with instead of a pager, like `difft` or `delta`. It's called with
+    /// the old and new version of every changed file.
+    #[arg(long, env = "FALCONF_DIFF_TOOL")]
+    pub diff_tool: Option<String>,
+
+    /// Whether `push` asks for confirmation before committing the diff it shows. [default: true]
+    #[arg(long, env = "FALCONF_CONFIRM")]
+    pub confirm: Option<bool>,
+
+    /// Never ask for input, for unattended syncs (from cron or a systemd timer, for example).
+    /// Pieces that need input, like manual pieces, are deferred: they're left pending and
+    /// reported. Confirmations are answered with no. This is the default when stdin is not a
+    /// terminal.
+    #[arg(long, env = "FALCONF_NO_INPUT")]
+    pub no_input: bool,
+
+    /// Like `--no-input`, but answer confirmations with yes.
+    #[arg(long, short, env = "FALCONF_YES")]
+    pub yes: bool,
+
+    /// How to resolve conflicts between files and the versions in the repo without input.
+    /// Directories that already exist are only overwritten with `theirs`. [default: defer]
+    #[arg(long, value_enum, env = "FALCONF_ON_CONFLICT")]
+    pub on_conflict: Option<OnConflict>,
+
+    /// The git branch of the repo. [default: main]
+    #[arg(long, env = "FALCONF_BRANCH")]
+    pub branch: Option<String>,
+
+    /// The name falconf commits with. [default: falconf]
+    #[arg(long, env = "FALCONF_COMMIT_NAME")]
+    pub commit_name: Option<String>,
+
+    /// The email address falconf commits with. [default: falconf@example.com]
+    #[arg(long, env = "FALCONF_COMMIT_EMAIL")]
+    pub commit_email: Option<String>,
+
     /// Don't execute any commands, but mark pieces as executed. WARNING: this
     /// is not safe to use, and is meant for testing purposes only.
     #[arg(long)]
     pub test_run: bool,
+
+    /// The settings in effect, combined from the above and the config files
+    #[arg(skip)]
+    pub config: Config,
 }
 
 impl TopLevelArgs {
@@ -64,18 +143,60 @@ impl TopLevelArgs {
         if self.verbose {
             "debug"
         } else {
-            &self.log_level
+            &self.config.log_level
+        }
+    }
+
+    /// Whether to ask the user for input, see `--no-input` and `--yes`
+    pub fn input(&self) -> Input {
+        if self.yes {
+            Input::Yes
+        } else if self.no_input || !io::stdin().is_terminal() {
+            Input::No
+        } else {
+            Input::Interactive
         }
     }
 
+    /// Read the config files, see `Config::load`
+    fn load_config(mut self) -> Result<Self> {
+        let repo_config = Installation::get_repository_path(&self.path).join(REPO_CONFIG_FILE);
+        self.config = Config::load(&self, &repo_config)?;
+        Ok(self)
+    }
+
     #[cfg(test)]
-    pub const fn new_testing(falconf_path: PathBuf, test_run: bool) -> Self {
+    pub fn new_testing(falconf_path: PathBuf, test_run: bool) -> Self {
         Self {
-            log_level: String::new(),
+            log_level: None,
             verbose: false,
+            config_file: falconf_path.join("config.toml"),
             path: falconf_path,
-            // dry_run: false,
+            dry_run: false,
+            escalate: None,
+            shell: None,
+            apt: None,
+            file_dir: None,
+            pager: None,
+            diff_tool: None,
+            confirm: None,
+            // Tests must never wait for input
+            no_input: true,
+            yes: false,
+            on_conflict: None,
+            branch: None,
+            commit_name: None,
+            commit_email: None,
             test_run,
+            config: Config::default(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn new_testing_dry_run(falconf_path: PathBuf) -> Self {
+        Self {
+            dry_run: true,
+            ..Self::new_testing(falconf_path, false)
         }
     }
 }
@@ -100,15 +221,41 @@ enum Commands {
     #[command(about = "Remove a piece")]
     Remove(remove::Args),
 
+    #[command(about = "Move a tracked file to a new location")]
+    Mv(mv::Args),
+
     #[command(about = "Push local changes in files to the repo")]
     Push(push::Args),
 
+    #[command(about = "Manage the machines in the repo")]
+    Machine(machine::Args),
+
+    #[command(
+        about = "Show the sync state of this machine. Exits with a non-zero exit code if anything is pending"
+    )]
+    Status(status::Args),
+
     #[command(
-        about = "Edit a piece. The value of a piece cannot be edited, create a new piece instead"
+        about = "Edit a piece. The value of a piece cannot be edited, create a new piece instead, or use `mv` to move a file"
     )]
     Edit(edit::Args),
 }
 
+impl Commands {
+    const fn supports_dry_run(&self) -> bool {
+        match self {
+            Self::Sync(_)
+            | Self::Add(_)
+            | Self::List(_)
+            | Self::Undo(_)
+            | Self::Remove(_)
+            | Self::Mv(_)
+            | Self::Status(_) => true,
+            Self::Init(_) | Self::Push(_) | Self::Machine(_) | Self::Edit(_) => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum PieceRef {
     Last,
@@ -139,8 +286,9 @@ fn parse_piece_ref(s: &str) -> Result<PieceRef, String> {
         .map(PieceRef::Id)
 }
 
-pub fn main() -> Result<()> {
-    let cli = Cli::parse();
+pub fn main() -> Result<ExitCode> {
+    let mut cli = Cli::parse();
+    cli.top_level = cli.top_level.load_config()?;
 
     env_logger::Builder::new()
         .filter_level(LevelFilter::from_str(cli.top_level.effective_log_level())?)
@@ -150,6 +298,10 @@ pub fn main() -> Result<()> {
 
     let Cli { command, top_level } = cli;
 
+    if top_level.dry_run && !command.supports_dry_run() {
+        return Err(eyre!("`--dry-run` is not supported for this command"));
+    }
+
     match *command {
         Commands::Init(args) => init::init(top_level, args),
         Commands::Sync(args) => sync::sync(top_level, args),
@@ -157,7 +309,13 @@ pub fn main() -> Result<()> {
         Commands::List(args) => list::list(top_level, args, &mut io::stdout().lock()),
         Commands::Undo(args) => undo::undo(top_level, args),
         Commands::Remove(args) => remove::remove(top_level, args),
+        Commands::Mv(args) => mv::mv(top_level, args),
         Commands::Push(args) => push::push(top_level, args),
+        Commands::Machine(args) => machine::machine(top_level, args, &mut io::stdout().lock()),
+        Commands::Status(args) => {
+            return status::status(top_level, args, &mut io::stdout().lock());
+        }
         Commands::Edit(args) => edit::edit(top_level, args),
     }
+    .map(|()| ExitCode::SUCCESS)
 }
@@ -0,0 +1,150 @@
+use crate::cli::{PieceRef, TopLevelArgs, parse_path, parse_piece_ref};
+use crate::execution_data::ExecutionData;
+use crate::installation::Installation;
+use color_eyre::eyre::{OptionExt as _, Result, WrapErr as _, eyre};
+use std::path::{PathBuf, absolute};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Specify the piece id. '-' is a shortcut for the last piece.
+    #[clap(value_parser = parse_piece_ref)]
+    pub(crate) piece: PieceRef,
+
+    /// The new location of the file
+    #[clap(value_parser = parse_path)]
+    pub location: PathBuf,
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub fn mv(top_level_args: TopLevelArgs, args: Args) -> Result<()> {
+    let mut installation = Installation::get(&top_level_args)?;
+    let execution_data = ExecutionData::new(&installation, &top_level_args)?;
+    installation.pull_and_read(true)?;
+    let repo = installation.repo_mut();
+    let pieces = repo.data_mut().pieces_mut();
+
+    let location = absolute(&args.location).wrap_err("Failed to get absolute path")?;
+    if pieces
+        .values()
+        .any(|piece| piece.file() == location.strip_prefix("/").ok())
+    {
+        return Err(eyre!("{} is already tracked", location.display()));
+    }
+
+    let id = args.piece.resolve(pieces)?;
+    let piece = pieces.get_mut(&id).ok_or_eyre("Piece not found")?;
+    let (old, new) = piece.move_file(id, location, &execution_data)?;
+
+    repo.write_and_push_unless_dry_run(vec![old, new])?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::add::tests::add_util_no_test_run;
+    use crate::cli::init::tests::init_util;
+    use crate::cli::sync::{self, sync};
+    use crate::cli::undo::tests::undo_util;
+    use crate::testing::TestRemote;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_mv() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let old = temp.path().join("old");
+        let new = temp.path().join("dir").join("new");
+        let newer = temp.path().join("newer");
+        fs::write(&old, "content")?;
+
+        let local_1 = init_util(&remote, true)?;
+        let old_s = old.to_str().ok_or_eyre("Invalid path")?.to_string();
+        add_util_no_test_run(local_1.path(), crate::cli::add::Piece::File, vec![old_s])?;
+
+        // Switching to being another machine
+        fs::remove_file(&old)?;
+        let local_2 = init_util(&remote, false)?;
+        let top_level_args_2 = TopLevelArgs::new_testing(local_2.path().clone(), false);
+        sync(top_level_args_2.clone(), sync::Args::default())?;
+        let link_2 = fs::read_link(&old)?;
+        fs::remove_file(&old)?;
+
+        // Moving it on the first machine
+        fs::write(&old, "local content")?;
+        mv(
+            TopLevelArgs::new_testing(local_1.path().clone(), false),
+            Args {
+                piece: PieceRef::Last,
+                location: new.clone(),
+            },
+        )?;
+        // Not our symlink, so it's left alone
+        assert_eq!(fs::read_to_string(&old)?, "local content");
+        assert!(new.is_symlink());
+        assert_eq!(fs::read_to_string(&new)?, "content");
+        fs::remove_file(&old)?;
+
+        // Moving it again before the second machine syncs
+        mv(
+            TopLevelArgs::new_testing(local_1.path().clone(), false),
+            Args {
+                piece: PieceRef::Last,
+                location: newer.clone(),
+            },
+        )?;
+        assert!(new.symlink_metadata().is_err());
+        assert!(newer.is_symlink());
+        fs::remove_file(&newer)?;
+
+        // Only the last location is in the repo
+        let repository = git2::Repository::open(Installation::get_repository_path(local_1.path()))?;
+        let tree = repository.head()?.peel_to_tree()?;
+        let in_tree = |location: &Path| -> Result<bool> {
+            let path = Path::new("files").join(location.strip_prefix("/")?);
+            Ok(tree.get_path(&path).is_ok())
+        };
+        assert!(!in_tree(&old)?);
+        assert!(!in_tree(&new)?);
+        assert!(in_tree(&newer)?);
+
+        // The second machine moves it when it syncs, even though it missed the first move
+        std::os::unix::fs::symlink(&link_2, &old)?;
+        sync(top_level_args_2, sync::Args::default())?;
+        assert!(old.symlink_metadata().is_err());
+        assert!(new.symlink_metadata().is_err());
+        assert!(newer.is_symlink());
+        assert_eq!(fs::read_to_string(&newer)?, "content");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mv_undone() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let old = temp.path().join("old");
+        fs::write(&old, "content")?;
+
+        let local = init_util(&remote, true)?;
+        let old_s = old.to_str().ok_or_eyre("Invalid path")?.to_string();
+        add_util_no_test_run(local.path(), crate::cli::add::Piece::File, vec![old_s])?;
+        undo_util(local.path(), PieceRef::Last)?;
+
+        let result = mv(
+            TopLevelArgs::new_testing(local.path().clone(), false),
+            Args {
+                piece: PieceRef::Last,
+                location: temp.path().join("new"),
+            },
+        );
+        assert!(result.is_err());
+        let file_dir = Installation::get_repository_path(local.path()).join("files");
+        assert!(file_dir.join(old.strip_prefix("/")?).exists());
+
+        Ok(())
+    }
+}
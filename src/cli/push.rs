@@ -1,70 +1,413 @@
-use crate::cli::TopLevelArgs;
+use crate::cli::{PieceRef, TopLevelArgs, parse_path, parse_piece_ref};
+use crate::diff::DiffViewer;
+use crate::execution_data::ExecutionData;
+use crate::full_piece::FullPiece;
 use crate::installation::Installation;
-use crate::utils::confirm;
-use color_eyre::eyre::{Context as _, Result, eyre};
-use git2::DiffFormat;
+use crate::repo::{Changes, Repo};
+use crate::utils::{choose, confirm};
+use color_eyre::eyre::{OptionExt as _, Result, WrapErr as _, eyre};
+use diffy::{Line, Patch};
 use log::info;
-use std::path::Path;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf, absolute};
+use std::{io, slice};
 
 #[derive(clap::Args, Debug)]
-pub struct Args {}
+pub struct Args {
+    /// Only push the changes to these paths (files or directories), or to the files of these
+    /// pieces. '-' is a shortcut for the last piece. Paths that exist are always taken as paths,
+    /// not as piece ids.
+    #[clap(value_parser = parse_selected)]
+    pub(crate) selection: Vec<Selected>,
+
+    /// Use this commit message instead of the generated one
+    #[clap(short, long)]
+    pub message: Option<String>,
+
+    /// Choose for every file, or every hunk of a file, whether to push it. Changes that aren't
+    /// pushed are kept, and can be pushed later.
+    #[clap(short, long)]
+    pub interactive: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum Selected {
+    Piece(PieceRef),
+    Path(PathBuf),
+}
+
+fn parse_selected(s: &str) -> Result<Selected> {
+    let path = parse_path(s)?;
+    if path.symlink_metadata().is_ok() {
+        return Ok(Selected::Path(path));
+    }
+    Ok(parse_piece_ref(s).map_or(Selected::Path(path), Selected::Piece))
+}
 
 #[expect(clippy::print_stdout)]
 #[allow(clippy::needless_pass_by_value)]
-pub fn push(top_level_args: TopLevelArgs, _args: Args) -> Result<()> {
+pub fn push(top_level_args: TopLevelArgs, args: Args) -> Result<()> {
     let mut installation = Installation::get(&top_level_args)?;
-    installation.pull_and_read(true)?;
+    let machine = *installation.machine();
+    let execution_data = ExecutionData::new(&installation, &top_level_args)?;
+    // Local changes are what we're pushing, so only remind of remote changes
+    installation.pull_and_read(false)?;
+    installation.check_synced();
     let repo = installation.repo_mut();
 
-    // Get the diff
-    let diff = repo.diff_index_to_workdir()?;
+    let selection = if args.selection.is_empty() {
+        None
+    } else {
+        Some(selected_files(repo, &args.selection)?)
+    };
+    // Hardlinks and copies aren't edited in the repo directly, so collect their edits first.
+    // A selected file in a directory that's linked file by file selects the directory.
+    let metadata_changes = FullPiece::collect_local_edits_of(
+        repo.data_mut().pieces_mut(),
+        &machine,
+        &execution_data,
+        |file| {
+            selection.as_ref().is_none_or(|selection| {
+                selection
+                    .iter()
+                    .any(|selected| file.starts_with(selected) || selected.starts_with(file))
+            })
+        },
+    )?;
 
     // Get the changed files
-    let files: Vec<PathBuf> = diff
-        .deltas()
-        .filter_map(|d| d.new_file().path())
-        .map(|path| {
-            path.strip_prefix("files")
-                .wrap_err("A file not in files/ was changed in the repo")
-                .map(Path::to_path_buf)
-        })
-        .collect::<Result<_, _>>()?;
+    let mut files = repo.changed_files()?;
+    // The shared config and ignore file in the root of the repo, which aren't in any selection
+    let mut root = repo.changed_root_files()?;
+    if let Some(selection) = &selection {
+        files.retain(|file| selection.iter().any(|selected| file.starts_with(selected)));
+        root.clear();
+    }
 
     // If there are no changes, exit
-    if files.is_empty() {
+    if files.is_empty() && root.is_empty() && metadata_changes.is_empty() {
         info!("Repo is clean, there are no changes to commit");
         return Ok(());
     }
 
-    // Print the diff
-    // TODO(low): pass this to delta and/or a syntax highlighter
-    diff.print(DiffFormat::Patch, |_delta, _hunk, line| {
-        match line.origin() {
-            '+' | '-' | ' ' => print!("{}", line.origin()),
-            _ => {}
+    // Print the permission changes, which git doesn't see
+    for change in metadata_changes {
+        println!("{change}");
+    }
+
+    let changes = if args.interactive {
+        if !execution_data.input.is_interactive() {
+            return Err(eyre!("`--interactive` needs input, which is disabled"));
         }
-        #[expect(
-            clippy::missing_panics_doc,
-            reason = "Cannot handle the error properly in the closure, and should be utf-8 anyway"
-        )]
-        let line = str::from_utf8(line.content()).unwrap();
-        print!("{line}");
-        true
-    })?;
+        choose_changes(repo, files, root, &execution_data.diff_viewer)?
+    } else {
+        let mut diffs = repo.file_diffs(&files)?;
+        diffs.extend(repo.root_file_diffs(&root)?);
+        execution_data
+            .diff_viewer
+            .show(&mut io::stdout().lock(), &diffs)?;
 
-    if !confirm("The above diff will be committed. Do you want to continue?")? {
-        return Err(eyre!("Aborted"));
-    }
+        if top_level_args.config.confirm
+            && !execution_data
+                .input
+                .confirm("The above diff will be committed. Do you want to continue?")?
+        {
+            return Err(eyre!("Aborted"));
+        }
+        Changes {
+            files,
+            root,
+            ..Changes::default()
+        }
+    };
 
     // Push changes
-    repo.write_and_push(files)?;
+    repo.write_and_push_changes(Changes {
+        message: args.message,
+        ..changes
+    })?;
 
     Ok(())
 }
 
-// TODO(test): add tests
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-// }
+/// The selected files and directories, relative to the file dir
+fn selected_files(repo: &Repo, selection: &[Selected]) -> Result<Vec<PathBuf>> {
+    let pieces = repo.data().pieces();
+    selection
+        .iter()
+        .map(|selected| match selected {
+            Selected::Piece(piece) => {
+                let id = piece.resolve(pieces)?;
+                pieces
+                    .get(&id)
+                    .ok_or_else(|| eyre!("Piece {id:08x} not found"))?
+                    .file()
+                    .map(Path::to_path_buf)
+                    .ok_or_else(|| eyre!("Piece {id:08x} is not a file"))
+            }
+            Selected::Path(path) => Ok(absolute(path)
+                .wrap_err("Failed to get absolute path")?
+                .strip_prefix("/")
+                .wrap_err("Absolute path doesn't start with /")?
+                .to_path_buf()),
+        })
+        .collect()
+}
+
+/// Ask for every file, and optionally every hunk, whether to push it.
+/// Files in the root of the repo are only pushed entirely.
+#[expect(clippy::print_stdout)]
+fn choose_changes(
+    repo: &Repo,
+    files: Vec<PathBuf>,
+    root: Vec<String>,
+    viewer: &DiffViewer,
+) -> Result<Changes> {
+    let mut changes = Changes::default();
+    let diffs = repo.root_file_diffs(&root)?;
+    for (file, diff) in root.into_iter().zip(diffs) {
+        viewer.show(&mut io::stdout().lock(), slice::from_ref(&diff))?;
+        match choose("Push this file?", &["yes", "no", "quit"])? {
+            0 => changes.root.push(file),
+            1 => {}
+            _ => return Err(eyre!("Aborted")),
+        }
+    }
+    let diffs = repo.file_diffs(&files)?;
+    for (file, diff) in files.into_iter().zip(diffs) {
+        viewer.show(&mut io::stdout().lock(), slice::from_ref(&diff))?;
+
+        let text = match (&diff.old, &diff.new) {
+            (old, Some(new)) => old
+                .as_deref()
+                .map_or(Ok(""), str::from_utf8)
+                .ok()
+                .zip(str::from_utf8(new).ok()),
+            // Deleted files can only be pushed entirely
+            (_, None) => None,
+        };
+        let Some((old, new)) = text else {
+            match choose("Push this file?", &["yes", "no", "quit"])? {
+                0 => changes.files.push(file),
+                1 => {}
+                _ => return Err(eyre!("Aborted")),
+            }
+            continue;
+        };
+
+        let patch = diffy::create_patch(old, new);
+        match choose("Push this file?", &["yes", "no", "hunks", "quit"])? {
+            0 => changes.files.push(file),
+            1 => {}
+            2 => {
+                let mut selected = vec![];
+                for hunk in patch.hunks() {
+                    println!("@@ -{} +{} @@", hunk.old_range(), hunk.new_range());
+                    for line in hunk.lines() {
+                        let (origin, content) = match line {
+                            Line::Context(content) => (' ', content),
+                            Line::Delete(content) => ('-', content),
+                            Line::Insert(content) => ('+', content),
+                        };
+                        println!("{origin}{}", content.strip_suffix('\n').unwrap_or(content));
+                    }
+                    selected.push(confirm("Push this hunk?")?);
+                }
+                if selected.iter().all(|selected| *selected) {
+                    changes.files.push(file);
+                } else if selected.iter().any(|selected| *selected) {
+                    let content = apply_hunks(old, &patch, &selected)?;
+                    changes.partial.push((file, content.into_bytes()));
+                }
+            }
+            _ => return Err(eyre!("Aborted")),
+        }
+    }
+    Ok(changes)
+}
+
+/// Apply only the hunks of `patch` for which `selected` is true to `old`
+fn apply_hunks(old: &str, patch: &Patch<'_, str>, selected: &[bool]) -> Result<String> {
+    let old_lines: Vec<_> = old.split_inclusive('\n').collect();
+    let mut result = String::new();
+    let mut position = 0;
+    for (hunk, selected) in patch.hunks().iter().zip(selected) {
+        let range = hunk.old_range();
+        // Ranges are 1-based, except for empty ranges, which start after the given line
+        let start = if range.is_empty() {
+            range.start()
+        } else {
+            range.start() - 1
+        };
+        result.push_str(
+            &old_lines
+                .get(position..start)
+                .ok_or_eyre("Hunk doesn't fit the original content")?
+                .concat(),
+        );
+        for line in hunk.lines() {
+            match line {
+                Line::Context(content) => result.push_str(content),
+                Line::Delete(content) if !selected => result.push_str(content),
+                Line::Insert(content) if *selected => result.push_str(content),
+                Line::Delete(_) | Line::Insert(_) => {}
+            }
+        }
+        position = start + range.len();
+    }
+    result.push_str(
+        &old_lines
+            .get(position..)
+            .ok_or_eyre("Hunk doesn't fit the original content")?
+            .concat(),
+    );
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::add;
+    use crate::cli::init::tests::init_util;
+    use crate::testing::TestRemote;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt as _;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_apply_hunks() -> Result<()> {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\n";
+        let patch = diffy::create_patch(old, new);
+        assert_eq!(patch.hunks().len(), 2);
+
+        assert_eq!(
+            apply_hunks(old, &patch, &[true, false])?,
+            "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\n"
+        );
+        assert_eq!(
+            apply_hunks(old, &patch, &[false, true])?,
+            "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\n"
+        );
+        assert_eq!(apply_hunks(old, &patch, &[true, true])?, new);
+        assert_eq!(apply_hunks(old, &patch, &[false, false])?, old);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_permissions() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let file = temp.path().join("file");
+        fs::write(&file, "secret")?;
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600))?;
+
+        let local_1 = init_util(&remote, true)?;
+        add::add(
+            TopLevelArgs::new_testing(local_1.path().clone(), false),
+            add::tests::add_args_util(
+                Some(add::Piece::File),
+                vec![file.display().to_string()],
+                None,
+            ),
+        )?;
+        let push_from = |path: &PathBuf| {
+            let mut top_level_args = TopLevelArgs::new_testing(path.clone(), false);
+            top_level_args.config.confirm = false;
+            push(
+                top_level_args,
+                Args {
+                    selection: vec![],
+                    message: None,
+                    interactive: false,
+                },
+            )
+        };
+
+        // Machine 1 rewrites the file in its repo when it pulls the change, which must not be
+        // recorded as a permission change
+        let local_2 = init_util(&remote, false)?;
+        fs::write(
+            Installation::get_repository_path(local_2.path())
+                .join("files")
+                .join(file.strip_prefix("/")?),
+            "changed secret",
+        )?;
+        push_from(local_2.path())?;
+        push_from(local_1.path())?;
+
+        assert_eq!(fs::read_to_string(&file)?, "changed secret");
+        assert_eq!(fs::metadata(&file)?.permissions().mode() & 0o777, 0o600);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_selected() -> Result<()> {
+        // A file that's named like a piece id is selected by path
+        let name = format!("{:08x}", rand::random::<u32>());
+        let file = tempfile::Builder::new()
+            .prefix(&name)
+            .rand_bytes(0)
+            .tempfile_in(".")?;
+        assert!(matches!(parse_selected(&name)?, Selected::Path(path) if path == Path::new(&name)));
+        file.close()?;
+        assert!(matches!(
+            parse_selected(&name)?,
+            Selected::Piece(PieceRef::Id(id)) if format!("{id:08x}") == name
+        ));
+        assert!(matches!(
+            parse_selected("-")?,
+            Selected::Piece(PieceRef::Last)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_selected_metadata() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let selected = temp.path().join("selected");
+        let other = temp.path().join("other");
+
+        let local = init_util(&remote, true)?;
+        for file in [&selected, &other] {
+            fs::write(file, "content")?;
+            add::add(
+                TopLevelArgs::new_testing(local.path().clone(), false),
+                add::tests::add_args_util(
+                    Some(add::Piece::File),
+                    vec![file.display().to_string()],
+                    None,
+                ),
+            )?;
+            fs::set_permissions(file, fs::Permissions::from_mode(0o600))?;
+        }
+
+        let mut top_level_args = TopLevelArgs::new_testing(local.path().clone(), false);
+        top_level_args.config.confirm = false;
+        push(
+            top_level_args.clone(),
+            Args {
+                selection: vec![Selected::Path(selected.clone())],
+                message: None,
+                interactive: false,
+            },
+        )?;
+
+        // Only the permission change of the selected file was recorded
+        let mut installation = Installation::get(&top_level_args)?;
+        let machine = *installation.machine();
+        let execution_data = ExecutionData::new(&installation, &top_level_args)?;
+        let changes = FullPiece::collect_local_edits(
+            installation.repo_mut().data_mut().pieces_mut(),
+            &machine,
+            &execution_data,
+        )?;
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].starts_with(&format!("{}:", other.display())));
+
+        Ok(())
+    }
+}
@@ -4,21 +4,25 @@ use crate::installation::Installation;
 use color_eyre::eyre;
 use color_eyre::eyre::OptionExt as _;
 use color_eyre::eyre::Result;
+use log::info;
 use std::fs::remove_file;
 
-// TODO(low): add a command to remove all unused pieces
 #[derive(clap::Args, Debug)]
 pub struct Args {
     /// Specify piece ids. '-' is a shortcut for the last piece.
     #[clap(
         value_parser = parse_piece_ref,
-        required = true
+        required_unless_present = "unused"
     )]
     pub(crate) pieces: Vec<PieceRef>,
 
     /// Remove the piece even if it is not unused
     #[arg(long, short)]
     pub force: bool,
+
+    /// Remove all unused pieces, like undone pieces and one-time pieces that are done everywhere
+    #[arg(long, conflicts_with_all = ["pieces", "force"])]
+    pub unused: bool,
 }
 
 #[allow(clippy::needless_pass_by_value)]
@@ -29,11 +33,18 @@ pub fn remove(top_level_args: TopLevelArgs, args: Args) -> Result<()> {
     let file_dir = repo.file_dir()?;
     let pieces = repo.data().pieces();
 
-    let piece_ids = args
-        .pieces
-        .iter()
-        .map(|x| x.resolve(pieces))
-        .collect::<Result<Vec<_>>>()?;
+    let piece_ids = if args.unused {
+        pieces
+            .iter()
+            .filter(|(_id, piece)| piece.unused(repo.data().machines()))
+            .map(|(id, _piece)| *id)
+            .collect()
+    } else {
+        args.pieces
+            .iter()
+            .map(|x| x.resolve(pieces))
+            .collect::<Result<Vec<_>>>()?
+    };
 
     let pieces_to_remove = piece_ids
         .iter()
@@ -42,7 +53,7 @@ pub fn remove(top_level_args: TopLevelArgs, args: Args) -> Result<()> {
 
     // Check if it's unused
     for piece in &pieces_to_remove {
-        if !args.force && !piece.unused() {
+        if !args.force && !piece.unused(repo.data().machines()) {
             return Err(eyre::eyre!(
                 "Piece is still in use. Pass --force to remove it anyway, without undoing."
             ));
@@ -54,10 +65,16 @@ pub fn remove(top_level_args: TopLevelArgs, args: Args) -> Result<()> {
     for piece in pieces_to_remove {
         if let Some(file) = piece.file() {
             removed_files.push(file.to_path_buf());
-            remove_file(file_dir.join(file))?;
+            if top_level_args.dry_run {
+                info!("Dry run! Would remove {}", file_dir.join(file).display());
+            } else {
+                remove_file(file_dir.join(file))?;
+            }
         }
     }
-    repo.clean_file_dir()?;
+    if !top_level_args.dry_run {
+        repo.clean_file_dir()?;
+    }
 
     let pieces = repo.data_mut().pieces_mut();
 
@@ -68,7 +85,7 @@ pub fn remove(top_level_args: TopLevelArgs, args: Args) -> Result<()> {
 
     // Push changes
     // Not much to fail, we don't need to split writes
-    repo.write_and_push(removed_files)?;
+    repo.write_and_push_unless_dry_run(removed_files)?;
 
     Ok(())
 }
@@ -0,0 +1,204 @@
+use crate::cli::TopLevelArgs;
+use crate::diff::DiffViewer;
+use crate::full_piece::FullPiece;
+use crate::installation::Installation;
+use color_eyre::Result;
+use std::io::Write;
+use std::process::ExitCode;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Also show the diffs of the tracked files with local changes
+    #[clap(short, long)]
+    pub diff: bool,
+}
+
+/// Returns `ExitCode::FAILURE` if anything is pending, so this can be used in shell prompts and monitoring
+#[allow(clippy::needless_pass_by_value)]
+pub fn status<W: Write>(
+    top_level_args: TopLevelArgs,
+    args: Args,
+    writer: &mut W,
+) -> Result<ExitCode> {
+    let installation = Installation::get(&top_level_args)?;
+    let machine = *installation.machine();
+    // Only fetch: a status doesn't change anything, and pulling could merge and push
+    let repo = installation.repo();
+    let (ahead, behind) = repo.ahead_behind()?;
+    let changed_files = repo.changed_files()?;
+    let remote_branch = repo.remote_branch();
+    let diffs = if args.diff {
+        repo.file_diffs(&changed_files)?
+    } else {
+        vec![]
+    };
+    // Pulling would fast-forward to the remote data. Diverged data is only merged by a sync.
+    let mut data = if behind > 0 && ahead == 0 {
+        repo.fetched_data()?
+    } else {
+        repo.data().clone()
+    };
+
+    let mut pending = false;
+
+    if behind > 0 {
+        pending = true;
+        writeln!(
+            writer,
+            "Local branch is {behind} commit(s) behind {} (use `falconf sync`)",
+            remote_branch
+        )?;
+    }
+    if ahead > 0 {
+        pending = true;
+        writeln!(
+            writer,
+            "Local branch is {ahead} commit(s) ahead of {}",
+            remote_branch
+        )?;
+    }
+
+    let (pieces, machines) = data.pieces_mut_and_machines();
+    let (to_execute, to_undo) = FullPiece::get_todo(pieces, machines, &machine);
+    if !to_execute.is_empty() || !to_undo.is_empty() {
+        pending = true;
+        writeln!(writer, "Pending on this machine (use `falconf sync`):")?;
+        for (id, piece) in to_execute {
+            writeln!(writer, "- Execute: {}", piece.print(id, machines))?;
+        }
+        for (id, piece) in to_undo {
+            writeln!(writer, "- Undo: {}", piece.print(id, machines))?;
+        }
+    }
+
+    if !changed_files.is_empty() {
+        pending = true;
+        writeln!(
+            writer,
+            "Tracked files with local changes (use `falconf push`):"
+        )?;
+        for file in changed_files {
+            writeln!(writer, "- /{}", file.display())?;
+        }
+        DiffViewer::new(&top_level_args)?.show(writer, &diffs)?;
+    }
+
+    let satisfied = data
+        .pieces()
+        .iter()
+        .filter(|(_id, piece)| piece.satisfied_on(&machine))
+        .collect::<Vec<_>>();
+    if !satisfied.is_empty() {
+        writeln!(
+            writer,
+            "Already satisfied on this machine, so marked as done without executing:"
+        )?;
+        for (id, piece) in satisfied {
+            writeln!(writer, "- {}", piece.print(*id, data.machines()))?;
+        }
+    }
+
+    let unused = data
+        .pieces()
+        .iter()
+        .filter(|(_id, piece)| piece.unused(data.machines()))
+        .collect::<Vec<_>>();
+    if !unused.is_empty() {
+        // Unused pieces don't need any action on this machine, so they aren't pending
+        writeln!(writer, "Unused pieces (use `falconf remove`):")?;
+        for (id, piece) in unused {
+            writeln!(writer, "- {}", piece.print(*id, data.machines()))?;
+        }
+    }
+
+    if pending {
+        Ok(ExitCode::FAILURE)
+    } else {
+        writeln!(writer, "Everything is in sync")?;
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::add::tests::{add_util, add_util_no_test_run};
+    use crate::cli::init::tests::init_util;
+    use crate::cli::{add, sync};
+    use crate::testing::TestRemote;
+    use color_eyre::eyre::OptionExt as _;
+    use std::io;
+
+    fn status_util(falconf_path: &std::path::Path) -> Result<(ExitCode, String)> {
+        let top_level_args = TopLevelArgs::new_testing(falconf_path.to_path_buf(), true);
+        let mut writer = io::Cursor::new(vec![]);
+        let exit_code = status(top_level_args, Args { diff: true }, &mut writer)?;
+        Ok((exit_code, String::from_utf8(writer.into_inner())?))
+    }
+
+    #[test]
+    fn test_status() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let local_1 = init_util(&remote, true)?;
+        let local_2 = init_util(&remote, false)?;
+
+        add_util(
+            local_1.path(),
+            add::Piece::Command,
+            vec![String::from("true")],
+        )?;
+
+        let (exit_code, output) = status_util(local_1.path())?;
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+        assert!(output.contains("Everything is in sync"));
+
+        // Nothing is pulled, so it's still behind the second time
+        let head = || -> Result<git2::Oid> {
+            git2::Repository::open(Installation::get_repository_path(local_2.path()))?
+                .head()?
+                .target()
+                .ok_or_eyre("Head has no target")
+        };
+        let head_before = head()?;
+        for _ in 0..2 {
+            let (exit_code, output) = status_util(local_2.path())?;
+            assert_eq!(exit_code, ExitCode::FAILURE);
+            assert!(output.contains("1 commit(s) behind"));
+            assert!(output.contains("- Execute:"));
+        }
+        assert_eq!(head()?, head_before);
+
+        sync::sync(
+            TopLevelArgs::new_testing(local_2.path().clone(), true),
+            sync::Args::default(),
+        )?;
+
+        let (exit_code, output) = status_util(local_2.path())?;
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+        assert!(output.contains("Everything is in sync"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_diff() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let local = init_util(&remote, true)?;
+        let temp = tempfile::TempDir::new()?;
+        let file = temp.path().join("file");
+        std::fs::write(&file, "old\n")?;
+        add_util_no_test_run(
+            local.path(),
+            add::Piece::File,
+            vec![file.display().to_string()],
+        )?;
+
+        std::fs::write(&file, "new\n")?;
+        let (exit_code, output) = status_util(local.path())?;
+        assert_eq!(exit_code, ExitCode::FAILURE);
+        assert!(output.contains(&format!("- {}\n", file.display())));
+        assert!(output.contains("-old\n+new\n"));
+
+        Ok(())
+    }
+}
@@ -3,29 +3,89 @@ use crate::execution_data::ExecutionData;
 use crate::full_piece::FullPiece;
 use crate::installation::Installation;
 use color_eyre::Result;
+use color_eyre::eyre::{OptionExt as _, eyre};
 use log::info;
 
-#[derive(clap::Args, Debug)]
-pub struct Args {}
+#[derive(clap::Args, Debug, Default)]
+pub struct Args {
+    /// Don't stop at a failing piece, but skip it (and the pieces that depend on it) and continue with the rest
+    #[arg(long, short)]
+    pub keep_going: bool,
+
+    /// Commit and push local changes to tracked files along with the sync, instead of warning
+    /// about them. [default: false]
+    #[arg(long, env = "FALCONF_AUTO_PUSH", num_args = 0..=1, default_missing_value = "true")]
+    pub auto_push: Option<bool>,
+}
 
 #[allow(clippy::needless_pass_by_value)]
-pub fn sync(top_level_args: TopLevelArgs, _args: Args) -> Result<()> {
+pub fn sync(top_level_args: TopLevelArgs, args: Args) -> Result<()> {
     let mut installation = Installation::get(&top_level_args)?;
     let machine = *installation.machine();
     let execution_data = ExecutionData::new(&installation, &top_level_args)?;
     installation.pull_and_read(false)?;
+    let auto_push = args.auto_push.unwrap_or(top_level_args.config.auto_push);
+    if !auto_push {
+        installation.check_local_changes();
+    }
     let repo = installation.repo_mut();
+
+    // Collected before executing, so local edits end up in the repo instead of conflicting
+    let files = if auto_push {
+        if !top_level_args.dry_run {
+            // Hardlinks and copies aren't edited in the repo directly, so collect their edits first
+            let metadata_changes = FullPiece::collect_local_edits(
+                repo.data_mut().pieces_mut(),
+                &machine,
+                &execution_data,
+            )?;
+            for change in metadata_changes {
+                info!("{change}");
+            }
+        }
+        repo.changed_files()?
+    } else {
+        vec![]
+    };
     let data = repo.data_mut();
 
     // Do out-of-sync (todo) changes
-    if let Err(err) = FullPiece::do_todo(data.pieces_mut(), &machine, &execution_data) {
-        info!("Found error during sync; writing and pushing the changes that *were* done");
-        repo.write_and_push(vec![])?;
-        return Err(err);
+    let (pieces, machines) = data.pieces_mut_and_machines();
+    let report =
+        match FullPiece::do_todo(pieces, machines, &machine, &execution_data, args.keep_going) {
+            Ok(report) => report,
+            Err(err) => {
+                info!("Found error during sync; writing and pushing the changes that *were* done");
+                repo.write_and_push_unless_dry_run(files)?;
+                return Err(err);
+            }
+        };
+
+    // Pick up changes to hardlinks and copies that were pulled
+    if !execution_data.test_run {
+        FullPiece::redeploy_files(repo.data().pieces(), &machine, &execution_data)?;
+    }
+
+    if !top_level_args.dry_run {
+        let changed = !files.is_empty() || repo.data_would_change()?;
+        repo.data_mut()
+            .machines_mut()
+            .get_mut(&machine)
+            .ok_or_eyre("This machine is not in the repo")?
+            .update_last_sync(changed)?;
     }
 
     // Push changes
-    repo.write_and_push(vec![])?;
+    repo.write_and_push_unless_dry_run(files)?;
+
+    // Deferred pieces are reported even without `--keep-going`, as they're easy to miss otherwise
+    if args.keep_going || report.deferred() > 0 {
+        report.print();
+        let failed = report.failed();
+        if failed > 0 {
+            return Err(eyre!("{failed} piece(s) failed"));
+        }
+    }
 
     Ok(())
 }
@@ -33,14 +93,15 @@ pub fn sync(top_level_args: TopLevelArgs, _args: Args) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::cli::add::tests::{add_util, add_util_no_test_run};
+    use crate::cli::add::tests::{add_util, add_util_no_test_run, add_util_once};
     use crate::cli::init::tests::init_util;
     use crate::cli::remove::remove;
     use crate::cli::undo::tests::undo_util;
-    use crate::cli::{add, remove};
+    use crate::cli::{PieceRef, add, remove};
     use crate::testing::{TestRemote, get_piece};
     use color_eyre::eyre::OptionExt;
     use log::debug;
+    use std::fs;
     use std::fs::{File, remove_file};
     use std::io::Write;
     use tempfile::TempDir;
@@ -79,10 +140,11 @@ mod tests {
             remove::Args {
                 pieces: vec![],
                 force: false,
+                unused: false,
             },
         )?;
         let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), false);
-        let args = Args {};
+        let args = Args::default();
         sync(top_level_args, args)?;
 
         // After syncing, the file is created
@@ -98,7 +160,7 @@ mod tests {
         assert!(test_1.is_symlink());
 
         let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), false);
-        let args = Args {};
+        let args = Args::default();
         sync(top_level_args, args)?;
 
         assert!(!test_1.exists());
@@ -106,6 +168,96 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_once() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let test_1 = temp.path().join("test_1.txt");
+
+        let local_1 = init_util(&remote, true)?;
+        let local_2 = init_util(&remote, false)?;
+        add_util_once(
+            local_1.path(),
+            add::Piece::Command,
+            vec![format!("touch '{}'", test_1.display())],
+        )?;
+        // Marked as done on local 1 without executing it
+        assert!(!test_1.exists());
+
+        // Local 2 existed when the piece was added, so it should execute it
+        let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), false);
+        sync(top_level_args, Args::default())?;
+        assert!(test_1.exists());
+        remove_file(&test_1)?;
+
+        // Local 3 didn't, so it shouldn't
+        let local_3 = init_util(&remote, false)?;
+        let top_level_args = TopLevelArgs::new_testing(local_3.path().clone(), false);
+        sync(top_level_args, Args::default())?;
+        assert!(!test_1.exists());
+
+        // It's done on every machine it should be done on, so it can be cleaned up
+        let top_level_args = TopLevelArgs::new_testing(local_1.path().clone(), false);
+        remove(
+            top_level_args,
+            remove::Args {
+                pieces: vec![],
+                force: false,
+                unused: true,
+            },
+        )?;
+        let top_level_args = TopLevelArgs::new_testing(local_1.path().clone(), false);
+        assert!(
+            Installation::get(&top_level_args)?
+                .repo()
+                .data()
+                .pieces()
+                .is_empty()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let test_1 = temp.path().join("test_1.txt");
+
+        let local_1 = init_util(&remote, true)?;
+        let local_2 = init_util(&remote, false)?;
+        // Added after local_2 was initialized, so local_2 has to pull it
+        add_util(
+            local_1.path(),
+            add::Piece::Command,
+            vec![format!("touch '{}'", test_1.display())],
+        )?;
+
+        let head_before = git2::Repository::open(local_2.path().join("repository"))?
+            .head()?
+            .peel_to_commit()?
+            .id();
+
+        let top_level_args = TopLevelArgs::new_testing_dry_run(local_2.path().clone());
+        sync(top_level_args, Args::default())?;
+
+        // Nothing was executed
+        assert!(!test_1.exists());
+        // The local repo wasn't fast-forwarded, and nothing was committed
+        let head_after = git2::Repository::open(local_2.path().join("repository"))?
+            .head()?
+            .peel_to_commit()?
+            .id();
+        assert_eq!(head_before, head_after);
+
+        // After a real sync the piece is executed and marked as done
+        let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), false);
+        sync(top_level_args, Args::default())?;
+        assert!(test_1.exists());
+
+        Ok(())
+    }
+
     #[test]
     fn test_atomic() -> Result<()> {
         let remote = TestRemote::new()?;
@@ -127,7 +279,7 @@ mod tests {
 
         let local_2 = init_util(&remote, false)?;
         let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), false);
-        assert!(sync(top_level_args, Args {}).is_err());
+        assert!(sync(top_level_args, Args::default()).is_err());
 
         let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), true);
         let installation = Installation::get(&top_level_args)?;
@@ -158,4 +310,253 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_after() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let test_1 = temp.path().join("test_1.txt");
+
+        let local_1 = init_util(&remote, true)?;
+        let local_2 = init_util(&remote, false)?;
+        let add_appending = |value: &str, after: Vec<PieceRef>| {
+            let mut args = add::tests::add_args_util(
+                Some(add::Piece::Command),
+                vec![String::from("true")],
+                None,
+            );
+            args.undo = Some(format!("echo {value} >> '{}'", test_1.display()));
+            args.after = after;
+            add::add(
+                TopLevelArgs::new_testing(local_1.path().clone(), true),
+                args,
+            )
+        };
+        add_appending("a", vec![])?;
+        add_appending("b", vec![PieceRef::Last])?;
+        sync(
+            TopLevelArgs::new_testing(local_2.path().clone(), false),
+            Args::default(),
+        )?;
+
+        undo_util(local_1.path(), get_piece(local_1.path(), 0)?)?;
+        undo_util(local_1.path(), get_piece(local_1.path(), 1)?)?;
+        sync(
+            TopLevelArgs::new_testing(local_2.path().clone(), false),
+            Args::default(),
+        )?;
+        // The second piece depends on the first, so it's undone first
+        assert_eq!(fs::read_to_string(&test_1)?, "b\na\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let test_1 = temp.path().join("test_1.txt");
+        let test_2 = temp.path().join("test_2.txt");
+
+        let local_1 = init_util(&remote, true)?;
+        for (test, check) in [(&test_1, "true"), (&test_2, "false")] {
+            let mut args = add::tests::add_args_util(
+                Some(add::Piece::Command),
+                vec![format!("touch '{}'", test.display())],
+                None,
+            );
+            args.check = Some(String::from(check));
+            add::add(
+                TopLevelArgs::new_testing(local_1.path().clone(), true),
+                args,
+            )?;
+        }
+
+        let local_2 = init_util(&remote, false)?;
+        sync(
+            TopLevelArgs::new_testing(local_2.path().clone(), false),
+            Args::default(),
+        )?;
+        // The check of the first one succeeds, so it's not executed
+        assert!(!test_1.exists());
+        assert!(test_2.exists());
+
+        let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), true);
+        let installation = Installation::get(&top_level_args)?;
+        let pieces = installation.repo().data().pieces();
+        assert!(pieces[0].done_on().contains(installation.machine()));
+        assert!(pieces[0].satisfied_on(installation.machine()));
+        assert!(!pieces[1].satisfied_on(installation.machine()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_going() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let test_1 = temp.path().join("test_1.txt");
+        let test_2 = temp.path().join("test_2.txt");
+
+        let local_1 = init_util(&remote, true)?;
+        let add_command = |command: String, after: Vec<PieceRef>| {
+            let mut args =
+                add::tests::add_args_util(Some(add::Piece::Command), vec![command], None);
+            args.after = after;
+            add::add(
+                TopLevelArgs::new_testing(local_1.path().clone(), true),
+                args,
+            )
+        };
+        // Always fails
+        add_command(String::from("false"), vec![])?;
+        // Depends on the failing one, so it's skipped
+        add_command(
+            format!("touch '{}'", test_1.display()),
+            vec![PieceRef::Last],
+        )?;
+        // Independent, so it still runs
+        add_command(format!("touch '{}'", test_2.display()), vec![])?;
+
+        let local_2 = init_util(&remote, false)?;
+        let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), false);
+        assert!(
+            sync(
+                top_level_args,
+                Args {
+                    keep_going: true,
+                    ..Default::default()
+                }
+            )
+            .is_err()
+        );
+        assert!(!test_1.exists());
+        assert!(test_2.exists());
+
+        let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), true);
+        let installation = Installation::get(&top_level_args)?;
+        let done = installation
+            .repo()
+            .data()
+            .pieces()
+            .values()
+            .map(|piece| piece.done_on().contains(installation.machine()))
+            .collect::<Vec<_>>();
+        assert_eq!(done, vec![false, false, true]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_push() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let file = temp.path().join("file");
+        fs::write(&file, "content")?;
+
+        let local_1 = init_util(&remote, true)?;
+        add_util_no_test_run(
+            local_1.path(),
+            add::Piece::File,
+            vec![file.display().to_string()],
+        )?;
+        fs::write(&file, "local content")?;
+
+        let top_level_args = TopLevelArgs::new_testing(local_1.path().clone(), false);
+        sync(
+            top_level_args.clone(),
+            Args {
+                auto_push: Some(true),
+                ..Default::default()
+            },
+        )?;
+        let installation = Installation::get(&top_level_args)?;
+        assert!(installation.repo().changed_files()?.is_empty());
+
+        let local_2 = init_util(&remote, false)?;
+        let installation =
+            Installation::get(&TopLevelArgs::new_testing(local_2.path().clone(), false))?;
+        let relative = file.strip_prefix("/")?;
+        assert_eq!(
+            fs::read_to_string(installation.repo().file_dir()?.join(relative))?,
+            "local content"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deferred() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let test_1 = temp.path().join("test_1.txt");
+        let test_2 = temp.path().join("test_2.txt");
+
+        let local_1 = init_util(&remote, true)?;
+        let add_piece = |piece: add::Piece, value: String, after: Vec<PieceRef>| {
+            let mut args = add::tests::add_args_util(Some(piece), vec![value], None);
+            args.after = after;
+            add::add(
+                TopLevelArgs::new_testing(local_1.path().clone(), true),
+                args,
+            )
+        };
+        // Needs input, so it's deferred
+        add_piece(add::Piece::Manual, String::from("Do something"), vec![])?;
+        // Depends on the deferred one, so it's skipped
+        add_piece(
+            add::Piece::Command,
+            format!("touch '{}'", test_1.display()),
+            vec![PieceRef::Last],
+        )?;
+        // Independent, so it still runs
+        add_piece(
+            add::Piece::Command,
+            format!("touch '{}'", test_2.display()),
+            vec![],
+        )?;
+
+        // Tests never ask for input, and deferring isn't a failure, even without `--keep-going`
+        let local_2 = init_util(&remote, false)?;
+        sync(
+            TopLevelArgs::new_testing(local_2.path().clone(), false),
+            Args::default(),
+        )?;
+        assert!(!test_1.exists());
+        assert!(test_2.exists());
+
+        let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), true);
+        let installation = Installation::get(&top_level_args)?;
+        let done = installation
+            .repo()
+            .data()
+            .pieces()
+            .values()
+            .map(|piece| piece.done_on().contains(installation.machine()))
+            .collect::<Vec<_>>();
+        assert_eq!(done, vec![false, false, true]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_without_changes() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let local = init_util(&remote, true)?;
+        let top_level_args = TopLevelArgs::new_testing(local.path().clone(), false);
+        let head = || -> Result<git2::Oid> {
+            git2::Repository::open(Installation::get_repository_path(local.path()))?
+                .head()?
+                .target()
+                .ok_or_eyre("Head has no target")
+        };
+
+        // The first sync is recorded, but syncs that don't change anything don't commit
+        sync(top_level_args.clone(), Args::default())?;
+        let synced = head()?;
+        sync(top_level_args, Args::default())?;
+        assert_eq!(head()?, synced);
+
+        Ok(())
+    }
 }
@@ -3,6 +3,7 @@ use crate::cli::{PieceRef, parse_piece_ref};
 use crate::execution_data::ExecutionData;
 use crate::full_piece::FullPiece;
 use crate::installation::Installation;
+use crate::pieces::file::UndoStrategy;
 use color_eyre::Result;
 use color_eyre::eyre::eyre;
 use log::info;
@@ -15,11 +16,38 @@ pub struct Args {
         value_parser = parse_piece_ref,
         required = true
     )]
-    pieces: Vec<PieceRef>,
+    pub(crate) pieces: Vec<PieceRef>,
 
     /// Do not undo the piece here (on this machine) immediately
     #[arg(long, short)]
     pub done_here: bool,
+
+    /// (file) Replace the symlink with a copy of the content in the repo
+    #[arg(long, conflicts_with_all = ["restore", "delete"])]
+    pub materialize: bool,
+
+    /// (file) Put back the content the file had before it was first linked on the machine
+    #[arg(long, conflicts_with_all = ["materialize", "delete"])]
+    pub restore: bool,
+
+    /// (file) Only remove the symlink. This is the default.
+    #[arg(long, conflicts_with_all = ["materialize", "restore"])]
+    pub delete: bool,
+}
+
+impl Args {
+    /// The undo strategy for file pieces, if one was given
+    pub const fn file_undo_strategy(&self) -> Option<UndoStrategy> {
+        if self.materialize {
+            Some(UndoStrategy::Materialize)
+        } else if self.restore {
+            Some(UndoStrategy::Restore)
+        } else if self.delete {
+            Some(UndoStrategy::Delete)
+        } else {
+            None
+        }
+    }
 }
 
 #[allow(clippy::needless_pass_by_value)]
@@ -50,13 +78,13 @@ pub fn undo(top_level_args: TopLevelArgs, args: Args) -> Result<()> {
     for (id, piece) in pieces_to_undo {
         if let Err(err) = piece.undo(id, &args, &execution_data) {
             info!("Found error during undo; writing and pushing the changes that *were* done");
-            repo.write_and_push(vec![])?;
+            repo.write_and_push_unless_dry_run(vec![])?;
             return Err(err);
         }
     }
 
     // Push changes
-    repo.write_and_push(vec![])?;
+    repo.write_and_push_unless_dry_run(vec![])?;
 
     Ok(())
 }
@@ -72,6 +100,9 @@ pub mod tests {
         let args = Args {
             pieces: vec![piece],
             done_here: true,
+            materialize: false,
+            restore: false,
+            delete: false,
         };
 
         undo(top_level_args, args)?;
@@ -0,0 +1,233 @@
+use crate::cli::TopLevelArgs;
+use clap::ValueEnum;
+use color_eyre::Result;
+use color_eyre::eyre::{WrapErr as _, eyre};
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Component, Path};
+
+/// The name of the config file in the root of the repo, which is shared across machines
+pub const REPO_CONFIG_FILE: &str = "falconf.toml";
+
+/// A config file. Every setting is optional, settings that are missing are taken from the next
+/// source in line (see `Config::load`).
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    branch: Option<String>,
+    commit_name: Option<String>,
+    commit_email: Option<String>,
+    shell: Option<String>,
+    escalate: Option<String>,
+    apt: Option<String>,
+    file_dir: Option<String>,
+    log_level: Option<String>,
+    pager: Option<String>,
+    diff_tool: Option<String>,
+    confirm: Option<bool>,
+    on_conflict: Option<OnConflict>,
+    auto_push: Option<bool>,
+}
+
+impl ConfigFile {
+    /// A missing file is an empty config
+    fn read(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content)
+                .wrap_err_with(|| format!("Failed to parse config file {}", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => {
+                Err(e).wrap_err_with(|| format!("Failed to read config file {}", path.display()))
+            }
+        }
+    }
+}
+
+/// How to resolve a conflict between a file and the version in the repo, when not asking for input
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    /// Leave the piece pending, so the conflict can be resolved in a later (interactive) sync
+    #[default]
+    Defer,
+    /// Keep the local version of files; it will be committed on the next push
+    Mine,
+    /// Overwrite the local version with the version in the repo
+    Theirs,
+}
+
+/// The settings in effect, see the `--help` of the options for what they do
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub branch: String,
+    pub commit_name: String,
+    pub commit_email: String,
+    pub shell: String,
+    pub escalate: String,
+    pub apt: String,
+    /// Relative to the root of the repo, checked to stay within it
+    pub file_dir: String,
+    pub log_level: String,
+    pub pager: Option<String>,
+    pub diff_tool: Option<String>,
+    pub confirm: bool,
+    pub on_conflict: OnConflict,
+    /// Only in the config files, `sync --auto-push` takes precedence
+    pub auto_push: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            branch: String::from("main"),
+            commit_name: String::from("falconf"),
+            commit_email: String::from("falconf@example.com"),
+            shell: String::from("bash"),
+            escalate: String::from("sudo"),
+            apt: String::from("apt"),
+            file_dir: String::from("files"),
+            log_level: String::from("info"),
+            pager: None,
+            diff_tool: None,
+            confirm: true,
+            on_conflict: OnConflict::default(),
+            auto_push: false,
+        }
+    }
+}
+
+impl Config {
+    /// Combine the settings from the command line and environment variables (already combined
+    /// in `args` by clap), the local config file, and the config file in the repo, in that order
+    /// of precedence. The repo config is read as it was after the last pull.
+    pub fn load(args: &TopLevelArgs, repo_config: &Path) -> Result<Self> {
+        let local = ConfigFile::read(&args.config_file)?;
+        let repo = ConfigFile::read(repo_config)?;
+        let default = Self::default();
+        let file_dir = args
+            .file_dir
+            .clone()
+            .or(local.file_dir)
+            .or(repo.file_dir)
+            .unwrap_or(default.file_dir);
+        if file_dir.is_empty()
+            || !Path::new(&file_dir)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(eyre!(
+                "The file dir must be a directory within the repo, like 'files', got '{file_dir}'"
+            ));
+        }
+        Ok(Self {
+            branch: args
+                .branch
+                .clone()
+                .or(local.branch)
+                .or(repo.branch)
+                .unwrap_or(default.branch),
+            commit_name: args
+                .commit_name
+                .clone()
+                .or(local.commit_name)
+                .or(repo.commit_name)
+                .unwrap_or(default.commit_name),
+            commit_email: args
+                .commit_email
+                .clone()
+                .or(local.commit_email)
+                .or(repo.commit_email)
+                .unwrap_or(default.commit_email),
+            shell: args
+                .shell
+                .clone()
+                .or(local.shell)
+                .or(repo.shell)
+                .unwrap_or(default.shell),
+            escalate: args
+                .escalate
+                .clone()
+                .or(local.escalate)
+                .or(repo.escalate)
+                .unwrap_or(default.escalate),
+            apt: args
+                .apt
+                .clone()
+                .or(local.apt)
+                .or(repo.apt)
+                .unwrap_or(default.apt),
+            file_dir,
+            log_level: args
+                .log_level
+                .clone()
+                .or(local.log_level)
+                .or(repo.log_level)
+                .unwrap_or(default.log_level),
+            pager: args.pager.clone().or(local.pager).or(repo.pager),
+            diff_tool: args
+                .diff_tool
+                .clone()
+                .or(local.diff_tool)
+                .or(repo.diff_tool),
+            confirm: args
+                .confirm
+                .or(local.confirm)
+                .or(repo.confirm)
+                .unwrap_or(default.confirm),
+            on_conflict: args
+                .on_conflict
+                .or(local.on_conflict)
+                .or(repo.on_conflict)
+                .unwrap_or(default.on_conflict),
+            auto_push: local
+                .auto_push
+                .or(repo.auto_push)
+                .unwrap_or(default.auto_push),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_precedence() -> Result<()> {
+        let temp = TempDir::new()?;
+        let mut args = TopLevelArgs::new_testing(temp.path().to_path_buf(), false);
+        let repo_config = temp.path().join(REPO_CONFIG_FILE);
+        fs::write(
+            &args.config_file,
+            "branch = \"local\"\nshell = \"zsh\"\nconfirm = false\nauto_push = true\n",
+        )?;
+        fs::write(
+            &repo_config,
+            "branch = \"repo\"\nshell = \"fish\"\nescalate = \"doas\"\non_conflict = \"theirs\"\nfile_dir = \"dotfiles\"\n",
+        )?;
+        args.shell = Some(String::from("sh"));
+
+        let config = Config::load(&args, &repo_config)?;
+        assert_eq!(config.shell, "sh");
+        assert_eq!(config.branch, "local");
+        assert_eq!(config.escalate, "doas");
+        assert_eq!(config.file_dir, "dotfiles");
+        assert_eq!(config.apt, "apt");
+        assert!(!config.confirm);
+        assert!(config.auto_push);
+        assert_eq!(config.commit_name, "falconf");
+        assert_eq!(config.on_conflict, OnConflict::Theirs);
+
+        fs::write(&args.config_file, "unknown = 1\n")?;
+        assert!(Config::load(&args, &repo_config).is_err());
+
+        // The file dir must stay within the repo
+        for file_dir in ["", ".", "../files", "/files"] {
+            args.file_dir = Some(String::from(file_dir));
+            assert!(Config::load(&args, &repo_config).is_err(), "{file_dir}");
+        }
+
+        Ok(())
+    }
+}
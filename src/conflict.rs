@@ -0,0 +1,309 @@
+use crate::diff::{DiffViewer, FileDiff};
+use crate::logging::CommandExt as _;
+use crate::utils::{binary_summary, choose};
+use color_eyre::Result;
+use color_eyre::eyre::{OptionExt as _, WrapErr as _, eyre};
+use git2::{ErrorCode, Oid, Repository};
+use std::io::Write as _;
+use std::path::Path;
+use std::process::Command;
+use std::{env, fs, io};
+
+/// A unified diff from the version in the repo (`theirs`) to the local version (`mine`)
+pub fn diff(theirs: &[u8], mine: &[u8]) -> String {
+    match (str::from_utf8(theirs), str::from_utf8(mine)) {
+        (Ok(theirs), Ok(mine)) => diffy::create_patch(theirs, mine).to_string(),
+        _ => binary_summary(Some(theirs), Some(mine)),
+    }
+}
+
+/// The version of a file in the file dir as it was in `commit`, the last synced version, to use
+/// as the base of a three-way merge. `None` if the file wasn't in the repo yet.
+pub fn synced_version(file_dir: &Path, commit: Oid, file: &Path) -> Result<Option<Vec<u8>>> {
+    let repository = Repository::discover(file_dir).wrap_err("Failed to open repository")?;
+    let workdir = repository.workdir().ok_or_eyre("Repository is bare")?;
+    let path = file_dir
+        .strip_prefix(workdir)
+        .wrap_err("File dir is outside the repository")?
+        .join(file);
+    let tree = repository
+        .find_commit(commit)
+        .wrap_err("Failed to find commit")?
+        .tree()
+        .wrap_err("Failed to get tree")?;
+    match tree.get_path(&path) {
+        Ok(entry) => Ok(Some(
+            entry
+                .to_object(&repository)
+                .wrap_err("Failed to get file object")?
+                .peel_to_blob()
+                .wrap_err("Failed to peel file to blob")?
+                .content()
+                .to_vec(),
+        )),
+        Err(err) if err.code() == ErrorCode::NotFound => Ok(None),
+        Err(err) => Err(err).wrap_err("Failed to find file in commit"),
+    }
+}
+
+/// Show the differences between the local version of a file and the version in the repo, and ask
+/// the user how to resolve them. `base` is the version that was last synced, if it's known.
+/// Returns the content to put in the repo, or `None` to take the version in the repo.
+#[expect(clippy::print_stdout)]
+pub fn resolve(
+    location: &Path,
+    mine: &[u8],
+    theirs: &[u8],
+    base: Option<&[u8]>,
+    viewer: &DiffViewer,
+) -> Result<Option<Vec<u8>>> {
+    println!(
+        "{} was edited locally, and is different from the version in the repo. Diff between the repo content and actual content:",
+        location.display()
+    );
+    viewer.show(
+        &mut io::stdout().lock(),
+        &[FileDiff {
+            path: location.to_path_buf(),
+            old: Some(theirs.to_vec()),
+            new: Some(mine.to_vec()),
+        }],
+    )?;
+
+    let text = as_text(mine, theirs, base);
+    let options = options(text);
+
+    loop {
+        let choice = choose(
+            "Keep mine (it will be committed on the next push), take theirs (the version in the repo), merge automatically, edit the conflicts, or quit?",
+            &options,
+        )?;
+        match options[choice] {
+            "mine" => return Ok(Some(mine.to_vec())),
+            "theirs" => return Ok(None),
+            "auto" => {
+                let (mine, theirs, base) = text.ok_or_eyre("Unreachable: only offered for text")?;
+                let base = base.ok_or_eyre("Unreachable: only offered with a base")?;
+                match diffy::merge(base, mine, theirs) {
+                    Ok(merged) => {
+                        println!("Merged without conflicts.");
+                        return Ok(Some(merged.into_bytes()));
+                    }
+                    Err(conflicts) => {
+                        println!("There are conflicts; opening them in your editor.");
+                        if let Some(merged) = edit(location, &conflicts)? {
+                            return Ok(Some(merged));
+                        }
+                    }
+                }
+            }
+            "edit" => {
+                let (mine, theirs, base) = text.ok_or_eyre("Unreachable: only offered for text")?;
+                // Without a base, the whole file is a conflict
+                let conflicts = diffy::merge(base.unwrap_or(""), mine, theirs)
+                    .unwrap_or_else(|conflicts| conflicts);
+                if let Some(merged) = edit(location, &conflicts)? {
+                    return Ok(Some(merged));
+                }
+            }
+            _ => return Err(eyre!("Aborted")),
+        }
+    }
+}
+
+/// The versions of a file, if they're all text
+fn as_text<'a>(
+    mine: &'a [u8],
+    theirs: &'a [u8],
+    base: Option<&'a [u8]>,
+) -> Option<(&'a str, &'a str, Option<&'a str>)> {
+    match (
+        str::from_utf8(mine),
+        str::from_utf8(theirs),
+        base.map(str::from_utf8).transpose(),
+    ) {
+        (Ok(mine), Ok(theirs), Ok(base)) => Some((mine, theirs, base)),
+        _ => None,
+    }
+}
+
+/// The ways a conflict can be resolved: merging only works for text, and merging automatically
+/// only with a base
+fn options(text: Option<(&str, &str, Option<&str>)>) -> Vec<&'static str> {
+    let mut options = vec!["mine", "theirs"];
+    if let Some((_, _, base)) = text {
+        if base.is_some() {
+            options.push("auto");
+        }
+        options.push("edit");
+    }
+    options.push("quit");
+    options
+}
+
+/// Let the user edit `content` in `$EDITOR`. Returns `None` if there are conflict markers left.
+fn edit(location: &Path, content: &str) -> Result<Option<Vec<u8>>> {
+    let editor = env::var("EDITOR").unwrap_or_else(|_| String::from("vi"));
+    let editor = shell_words::split(&editor).wrap_err("Failed to parse $EDITOR")?;
+    edit_with(&editor, location, content)
+}
+
+/// Like `edit`, with the editor split into arguments
+#[expect(clippy::print_stdout)]
+fn edit_with(editor: &[String], location: &Path, content: &str) -> Result<Option<Vec<u8>>> {
+    let (program, args) = editor.split_first().ok_or_eyre("$EDITOR is empty")?;
+
+    let name = location.file_name().map_or_else(
+        || String::from("file"),
+        |name| name.to_string_lossy().into_owned(),
+    );
+    // The content may be private, so the file is only readable by the user, and gets a random
+    // name so it can't be planted beforehand
+    let mut file = tempfile::Builder::new()
+        .prefix("falconf-merge-")
+        .suffix(&format!("-{name}"))
+        .tempfile()
+        .wrap_err("Failed to create file to edit")?;
+    file.write_all(content.as_bytes())
+        .wrap_err("Failed to write file to edit")?;
+    let status = Command::new(program)
+        .args(args)
+        .arg(file.path())
+        .status_checked();
+    // Read by path, as editors may replace the file instead of writing to it
+    let merged = fs::read(file.path()).wrap_err("Failed to read edited file");
+    file.close().wrap_err("Failed to remove edited file")?;
+    status?;
+    let merged = merged?;
+
+    if has_conflict_markers(&merged) {
+        println!("There are still conflict markers in the file.");
+        return Ok(None);
+    }
+    Ok(Some(merged))
+}
+
+fn has_conflict_markers(content: &[u8]) -> bool {
+    String::from_utf8_lossy(content)
+        .lines()
+        .any(|line| line.starts_with("<<<<<<<") || line.starts_with(">>>>>>>"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Signature;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(
+            diff(b"a\nb\n", b"a\nc\n"),
+            "--- original\n+++ modified\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n"
+        );
+        assert!(diff(&[0, 159], b"text").starts_with("Binary file changed: 2 bytes"));
+    }
+
+    #[test]
+    fn test_has_conflict_markers() {
+        assert!(!has_conflict_markers(b"a\n<<<<<< not quite\n"));
+        let conflicts = diffy::merge("a\nb\nc\n", "a\nx\nc\n", "a\ny\nc\n").unwrap_err();
+        assert!(has_conflict_markers(conflicts.as_bytes()));
+    }
+
+    #[test]
+    fn test_edit() -> Result<()> {
+        let location = Path::new("/home/user/.ssh/config");
+        let editor = |script: &str| ["sh", "-c", script].map(String::from);
+
+        // The editor gets a private file named after the location, with the content to edit
+        let edited = edit_with(
+            &editor(
+                r#"case "$0" in */falconf-merge-*-config) ;; *) exit 1 ;; esac; printf "%s %s" "$(stat -c %a "$0")" "$(cat "$0")" > "$0""#,
+            ),
+            location,
+            "content",
+        )?;
+        assert_eq!(edited, Some(b"600 content".to_vec()));
+
+        // Conflict markers left in the file aren't accepted
+        let conflicts = diffy::merge("a\nb\n", "a\nx\n", "a\ny\n").unwrap_err();
+        assert_eq!(edit_with(&editor("true"), location, &conflicts)?, None);
+
+        // A failing editor is an error
+        assert!(edit_with(&editor("exit 1"), location, "content").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_options() {
+        assert_eq!(
+            options(as_text(b"mine", b"theirs", Some(b"base"))),
+            ["mine", "theirs", "auto", "edit", "quit"]
+        );
+        assert_eq!(
+            options(as_text(b"mine", b"theirs", None)),
+            ["mine", "theirs", "edit", "quit"]
+        );
+        assert_eq!(
+            options(as_text(&[0, 159], b"theirs", None)),
+            ["mine", "theirs", "quit"]
+        );
+    }
+
+    #[test]
+    fn test_synced_version() -> Result<()> {
+        let temp = TempDir::new()?;
+        let repository = Repository::init(temp.path().join("repository"))?;
+        let file_dir = temp.path().join("repository").join("files");
+        let target = file_dir.join("dir").join("config");
+        let commit = |content: &str| -> Result<Oid> {
+            fs::create_dir_all(target.parent().ok_or_eyre("No parent")?)?;
+            fs::write(&target, content)?;
+            let mut index = repository.index()?;
+            index.add_path(Path::new("files/dir/config"))?;
+            let tree = repository.find_tree(index.write_tree()?)?;
+            let parent = repository
+                .head()
+                .ok()
+                .and_then(|head| head.peel_to_commit().ok());
+            let signature = Signature::now("test", "test@example.com")?;
+            Ok(repository.commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                "test",
+                &tree,
+                &parent.iter().collect::<Vec<_>>(),
+            )?)
+        };
+
+        // The file is deployed as a symlink, which is replaced by a local edit, while the version
+        // in the repo changes
+        let synced = commit("a\nb\nc\n")?;
+        let location = temp.path().join("config");
+        std::os::unix::fs::symlink(&target, &location)?;
+        commit("a\nb\nC\n")?;
+        fs::remove_file(&location)?;
+        fs::write(&location, "A\nb\nc\n")?;
+
+        // The base comes from the synced commit, not from what's deployed
+        let base = synced_version(&file_dir, synced, Path::new("dir/config"))?;
+        assert_eq!(base.as_deref(), Some(&b"a\nb\nc\n"[..]));
+        let mine = fs::read(&location)?;
+        let theirs = fs::read(&target)?;
+        let text = as_text(&mine, &theirs, base.as_deref());
+        assert!(options(text).contains(&"auto"));
+        let (mine, theirs, base) = text.ok_or_eyre("Not text")?;
+        assert_eq!(
+            diffy::merge(base.ok_or_eyre("No base")?, mine, theirs),
+            Ok(String::from("A\nb\nC\n"))
+        );
+
+        // Files that weren't in the repo yet have no base
+        assert_eq!(synced_version(&file_dir, synced, Path::new("other"))?, None);
+
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use crate::full_piece::FullPiece;
 use crate::machine::{Machine, MachineData};
 use color_eyre::Result;
+use color_eyre::eyre::eyre;
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use std::fs::File;
@@ -29,10 +30,128 @@ impl Data {
         &mut self.pieces
     }
 
+    pub const fn machines(&self) -> &IndexMap<Machine, MachineData> {
+        &self.machines
+    }
+
+    /// Both the pieces and the machines, for when the pieces are changed based on the machines
+    pub const fn pieces_mut_and_machines(
+        &mut self,
+    ) -> (
+        &mut IndexMap<u32, FullPiece>,
+        &IndexMap<Machine, MachineData>,
+    ) {
+        (&mut self.pieces, &self.machines)
+    }
+
+    /// Find a machine by its id (or a prefix of it), name, or hostname
+    pub fn find_machine(&self, reference: &str) -> Result<Machine> {
+        let matches = self
+            .machines
+            .iter()
+            .filter(|(machine, machine_data)| {
+                machine.0.to_string().starts_with(reference)
+                    || machine_data.name() == reference
+                    || machine_data.hostname() == reference
+            })
+            .map(|(machine, _machine_data)| *machine)
+            .collect::<Vec<_>>();
+        match matches.as_slice() {
+            [machine] => Ok(*machine),
+            [] => Err(eyre!("No machine found matching '{reference}'")),
+            _ => Err(eyre!(
+                "Multiple machines found matching '{reference}', use the machine id instead"
+            )),
+        }
+    }
+
     pub const fn machines_mut(&mut self) -> &mut IndexMap<Machine, MachineData> {
         &mut self.machines
     }
 
+    /// Remove a machine, and forget everything that was done on it
+    pub fn retire(&mut self, machine: &Machine) -> Result<()> {
+        self.machines
+            .shift_remove(machine)
+            .ok_or_else(|| eyre!("Machine {} not found", machine.0))?;
+        for piece in self.pieces.values_mut() {
+            piece.retain_machines(|m| m != machine);
+        }
+        Ok(())
+    }
+
+    /// Forget what was done on a machine, so everything is executed (again) on its next sync
+    pub fn reset_machine(&mut self, machine: &Machine) {
+        for piece in self.pieces.values_mut() {
+            piece.reset_machine(machine);
+        }
+    }
+
+    /// Three-way merge of the data file. Pieces and machines added on either side are kept,
+    /// and pieces and machines removed on either side are removed.
+    pub fn merge(base: &Self, ours: Self, theirs: Self) -> Result<Self> {
+        let mut their_pieces = theirs.pieces;
+        let mut pieces = IndexMap::new();
+        for (id, our_piece) in ours.pieces {
+            let base_piece = base.pieces.get(&id);
+            match their_pieces.shift_remove(&id) {
+                Some(their_piece) => {
+                    pieces.insert(
+                        id,
+                        FullPiece::merge(id, base_piece, our_piece, their_piece)?,
+                    );
+                }
+                // Removed on their side
+                None if base_piece.is_some() => {}
+                // Added on our side
+                None => {
+                    pieces.insert(id, our_piece);
+                }
+            }
+        }
+        // Added on their side (and not removed on our side)
+        pieces.extend(
+            their_pieces
+                .into_iter()
+                .filter(|(id, _piece)| !base.pieces.contains_key(id)),
+        );
+
+        let mut their_machines = theirs.machines;
+        let mut machines = IndexMap::new();
+        for (machine, our_machine_data) in ours.machines {
+            let base_machine_data = base.machines.get(&machine);
+            match their_machines.shift_remove(&machine) {
+                Some(their_machine_data) => {
+                    let machine_data =
+                        MachineData::merge(base_machine_data, our_machine_data, their_machine_data)
+                            .ok_or_else(|| {
+                                eyre!(
+                                    "Conflict: machine {} was changed differently on this machine and on the remote",
+                                    machine.0
+                                )
+                            })?;
+                    machines.insert(machine, machine_data);
+                }
+                None if base_machine_data.is_some() => {}
+                None => {
+                    machines.insert(machine, our_machine_data);
+                }
+            }
+        }
+        machines.extend(
+            their_machines
+                .into_iter()
+                .filter(|(machine, _machine_data)| !base.machines.contains_key(machine)),
+        );
+
+        // Machines that were retired on one side shouldn't come back through the other side
+        for piece in pieces.values_mut() {
+            piece.retain_machines(|machine| machines.contains_key(machine));
+        }
+
+        Ok(Self { pieces, machines })
+    }
+
     pub fn from_file(path: &Path) -> Result<Self> {
         let file = File::open(path)?;
         let reader = BufReader::new(file);
@@ -40,9 +159,20 @@ impl Data {
         Ok(data)
     }
 
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(ron::de::from_bytes(bytes)?)
+    }
+
+    pub fn to_ron(&self) -> Result<String> {
+        Ok(ron::ser::to_string_pretty(
+            self,
+            ron::ser::PrettyConfig::default(),
+        )?)
+    }
+
     pub fn to_file(&self, path: &Path) -> Result<()> {
         let file = File::create(path)?;
-        let string = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        let string = self.to_ron()?;
         let mut writer = BufWriter::new(file);
         writer.write_all(string.as_bytes())?;
         Ok(())
@@ -0,0 +1,250 @@
+use crate::cli::TopLevelArgs;
+use crate::utils::binary_summary;
+use color_eyre::Result;
+use color_eyre::eyre::{OptionExt as _, WrapErr as _};
+use command_error::{ChildExt as _, CommandExt as _};
+use diffy::PatchFormatter;
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal as _, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use uuid::Uuid;
+
+/// The change to a single file. `None` means the file didn't or doesn't exist.
+#[derive(Debug)]
+pub struct FileDiff {
+    /// The name shown for the file
+    pub path: PathBuf,
+    pub old: Option<Vec<u8>>,
+    pub new: Option<Vec<u8>>,
+}
+
+/// How diffs are shown to the user
+#[derive(Debug, Clone)]
+pub enum DiffViewer {
+    /// Write the diff as a unified diff, in color if `color`
+    Print { color: bool },
+    /// Pipe the colored diff through a pager, like `less -R` or `delta`
+    Pager(Vec<String>),
+    /// Call a diff tool, like `difft` or `delta`, with the old and new version of every file
+    Tool(Vec<String>),
+}
+
+impl DiffViewer {
+    /// A diff tool takes precedence over a pager. The pager defaults to git's `core.pager`.
+    /// When stdout isn't a terminal, the diff is always written plainly.
+    pub fn new(top_level_args: &TopLevelArgs) -> Result<Self> {
+        if !io::stdout().is_terminal() {
+            return Ok(Self::Print { color: false });
+        }
+        if let Some(tool) = &top_level_args.config.diff_tool {
+            return Ok(Self::Tool(
+                shell_words::split(tool).wrap_err("Failed to parse the diff tool")?,
+            ));
+        }
+        let pager = top_level_args.config.pager.clone().or_else(|| {
+            git2::Config::open_default()
+                .and_then(|config| config.get_string("core.pager"))
+                .ok()
+        });
+        let pager = pager
+            .map(|pager| shell_words::split(&pager).wrap_err("Failed to parse the pager"))
+            .transpose()?
+            .unwrap_or_default();
+        // Like git, `cat` disables the pager
+        if pager.is_empty() || pager == ["cat"] {
+            Ok(Self::Print {
+                color: env::var_os("NO_COLOR").is_none(),
+            })
+        } else {
+            Ok(Self::Pager(pager))
+        }
+    }
+
+    /// Show `diffs`. Printed diffs are written to `writer`, which is flushed before a pager or
+    /// diff tool takes over the terminal.
+    pub fn show<W: Write>(&self, writer: &mut W, diffs: &[FileDiff]) -> Result<()> {
+        match self {
+            Self::Print { color } => render(writer, diffs, *color).wrap_err("Failed to write diff"),
+            Self::Pager(pager) => {
+                writer.flush()?;
+                let (program, args) = pager.split_first().ok_or_eyre("The pager is empty")?;
+                let mut command = Command::new(program);
+                command.args(args).stdin(Stdio::piped());
+                // Like git, make `less` show colors and exit if the diff fits on the screen
+                if env::var_os("LESS").is_none() {
+                    command.env("LESS", "FRX");
+                }
+                let mut child = command.spawn_checked()?;
+                let mut stdin = child
+                    .child_mut()
+                    .stdin
+                    .take()
+                    .ok_or_eyre("Unreachable: stdin is piped")?;
+                let written = render(&mut stdin, diffs, true);
+                drop(stdin);
+                child.wait_checked()?;
+                // The pager quitting early closes the pipe, which isn't an error
+                match written {
+                    Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+                        Err(e).wrap_err("Failed to write diff to pager")
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Self::Tool(tool) => {
+                writer.flush()?;
+                let (program, args) = tool.split_first().ok_or_eyre("The diff tool is empty")?;
+                for diff in diffs {
+                    let dir = env::temp_dir().join(format!("falconf-diff-{}", Uuid::new_v4()));
+                    let result = Self::run_tool(program, args, diff, &dir);
+                    // Clean up before reporting the result; there may be nothing to clean up
+                    let _ = fs::remove_dir_all(&dir);
+                    result?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn run_tool(program: &str, args: &[String], diff: &FileDiff, dir: &Path) -> Result<()> {
+        // Named after the file, so the tool can show the name and detect the language
+        let name = diff.path.file_name().ok_or_eyre("File has no name")?;
+        let version = |side: &str, content: &Option<Vec<u8>>| -> Result<PathBuf> {
+            let Some(content) = content else {
+                return Ok(PathBuf::from("/dev/null"));
+            };
+            let path = dir.join(side).join(name);
+            fs::create_dir_all(dir.join(side)).wrap_err("Failed to create temporary directory")?;
+            fs::write(&path, content).wrap_err("Failed to write temporary file")?;
+            Ok(path)
+        };
+        let old = version("old", &diff.old)?;
+        let new = version("new", &diff.new)?;
+        Command::new(program)
+            .args(args)
+            .arg(old)
+            .arg(new)
+            // Diff tools exit with 1 when the files are different
+            .status_checked_with(|status| match status.code() {
+                Some(0 | 1) => Ok(()),
+                _ => Err(None::<String>),
+            })?;
+        Ok(())
+    }
+}
+
+/// Write `diffs` as unified diffs. Binary files are summarized by their size and hash, instead of
+/// shown line by line.
+pub fn render<W: Write>(writer: &mut W, diffs: &[FileDiff], color: bool) -> io::Result<()> {
+    let formatter = if color {
+        PatchFormatter::new().with_color()
+    } else {
+        PatchFormatter::new()
+    };
+    for diff in diffs {
+        // Paths are usually absolute, git-style prefixes are added instead
+        let path = diff.path.strip_prefix("/").unwrap_or(&diff.path).display();
+        if let (Some(old), Some(new)) = (as_text(diff.old.as_deref()), as_text(diff.new.as_deref()))
+        {
+            let mut options = diffy::DiffOptions::new();
+            options
+                .set_original_filename(if diff.old.is_some() {
+                    format!("a/{path}")
+                } else {
+                    String::from("/dev/null")
+                })
+                .set_modified_filename(if diff.new.is_some() {
+                    format!("b/{path}")
+                } else {
+                    String::from("/dev/null")
+                });
+            let patch = options.create_patch(old, new);
+            write!(writer, "{}", formatter.fmt_patch(&patch))?;
+        } else {
+            writeln!(writer, "--- a/{path}\n+++ b/{path}")?;
+            write!(
+                writer,
+                "{}",
+                binary_summary(diff.old.as_deref(), diff.new.as_deref())
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// A missing file is empty, binary files aren't text
+fn as_text(content: Option<&[u8]>) -> Option<&str> {
+    content.map_or(Some(""), |content| str::from_utf8(content).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() -> Result<()> {
+        let diffs = [
+            FileDiff {
+                path: PathBuf::from("changed"),
+                old: Some(b"line\n".to_vec()),
+                new: Some(b"changed line\n".to_vec()),
+            },
+            FileDiff {
+                path: PathBuf::from("new"),
+                old: None,
+                new: Some(b"new\n".to_vec()),
+            },
+            FileDiff {
+                path: PathBuf::from("binary"),
+                old: Some(vec![0, 159, 146, 150]),
+                new: None,
+            },
+        ];
+        let mut out = vec![];
+        render(&mut out, &diffs, false)?;
+        let out = String::from_utf8(out)?;
+        assert!(out.contains("--- a/changed\n+++ b/changed\n@@ -1 +1 @@\n-line\n+changed line\n"));
+        assert!(out.contains("--- /dev/null\n+++ b/new\n@@ -0,0 +1 @@\n+new\n"));
+        assert!(out.contains(&format!(
+            "--- a/binary\n+++ b/binary\nBinary file changed: 4 bytes (md5 {:x}) -> nothing\n",
+            md5::compute([0, 159, 146, 150])
+        )));
+
+        let mut colored = vec![];
+        render(&mut colored, &diffs, true)?;
+        assert!(String::from_utf8(colored)?.contains("\u{1b}["));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tool() -> Result<()> {
+        // `diff` exits with 1 because the files are different, which isn't an error
+        DiffViewer::Tool(vec![String::from("diff"), String::from("-u")]).show(
+            &mut io::sink(),
+            &[FileDiff {
+                path: PathBuf::from("/etc/file"),
+                old: Some(b"old\n".to_vec()),
+                new: None,
+            }],
+        )?;
+        // Other exit codes are
+        let tool = ["sh", "-c", "exit 2"].map(String::from).to_vec();
+        assert!(
+            DiffViewer::Tool(tool)
+                .show(
+                    &mut io::sink(),
+                    &[FileDiff {
+                        path: PathBuf::from("file"),
+                        old: None,
+                        new: None,
+                    }],
+                )
+                .is_err()
+        );
+
+        Ok(())
+    }
+}
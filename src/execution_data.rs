@@ -1,14 +1,40 @@
 use crate::cli::TopLevelArgs;
+use crate::config::OnConflict;
+use crate::diff::DiffViewer;
 use crate::installation::Installation;
 use crate::machine::Machine;
+use crate::pieces::file::IGNORE_FILE;
+use crate::utils::Input;
 use color_eyre::Result;
+use color_eyre::eyre::WrapErr as _;
+use git2::Oid;
 use std::path::PathBuf;
 
 #[derive(Debug)]
 pub struct ExecutionData {
     pub file_dir: PathBuf,
+    /// Where the original content of files is kept, to restore it when undoing
+    pub backup_dir: PathBuf,
+    /// Where copies of the deployed hardlinks and copies are kept, to detect local edits
+    pub deployed_dir: PathBuf,
+    /// The global `.falconfignore`, in the root of the repo
+    pub ignore_file: PathBuf,
+    /// The commit that was checked out before pulling, with the versions of the files that were
+    /// last synced, to use as the base of three-way merges
+    pub synced_commit: Option<Oid>,
     pub machine: Machine,
-    // pub dry_run: bool,
+    pub dry_run: bool,
+    /// The command to get root privileges with, split into arguments
+    pub escalate: Vec<String>,
+    /// The shell to run command pieces with, split into arguments
+    pub shell: Vec<String>,
+    /// The command to install and remove packages with, split into arguments
+    pub apt: Vec<String>,
+    pub diff_viewer: DiffViewer,
+    /// Pieces that need input are deferred when the user can't be asked
+    pub input: Input,
+    /// How file conflicts are resolved when the user can't be asked
+    pub on_conflict: OnConflict,
     pub test_run: bool,
 }
 
@@ -16,9 +42,23 @@ impl ExecutionData {
     pub fn new(installation: &Installation, top_level_args: &TopLevelArgs) -> Result<Self> {
         Ok(Self {
             file_dir: installation.repo().file_dir()?,
+            backup_dir: Installation::get_backup_dir(&top_level_args.path),
+            deployed_dir: Installation::get_deployed_dir(&top_level_args.path),
+            ignore_file: installation.repo().workdir()?.join(IGNORE_FILE),
+            synced_commit: installation.repo().head_commit(),
             machine: *installation.machine(),
-            // dry_run: top_level_args.dry_run,
-            test_run: top_level_args.test_run,
+            dry_run: top_level_args.dry_run,
+            escalate: shell_words::split(&top_level_args.config.escalate)
+                .wrap_err("Failed to parse the escalation command")?,
+            shell: shell_words::split(&top_level_args.config.shell)
+                .wrap_err("Failed to parse the shell")?,
+            apt: shell_words::split(&top_level_args.config.apt)
+                .wrap_err("Failed to parse the apt command")?,
+            diff_viewer: DiffViewer::new(top_level_args)?,
+            input: top_level_args.input(),
+            on_conflict: top_level_args.config.on_conflict,
+            // A dry run takes precedence, so the pieces get to report what they would do
+            test_run: top_level_args.test_run && !top_level_args.dry_run,
         })
     }
 }
@@ -1,18 +1,23 @@
 use crate::cli::add;
 use crate::cli::undo;
 use crate::execution_data::ExecutionData;
-use crate::machine::Machine;
+use crate::machine::{Machine, MachineData};
 use crate::pieces::{NonBulkPieceEnum, PieceEnum};
-use crate::utils::{print_id, set_eq};
+use crate::report::{Deferred, Outcome, Report};
+use crate::target::Target;
+use crate::utils::{create_parent, merge_set, merge_value, print_id, set_eq};
 use color_eyre::Result;
-use color_eyre::eyre::eyre;
+use color_eyre::eyre::{WrapErr as _, eyre};
 use color_eyre::owo_colors::OwoColorize as _;
 use indexmap::IndexMap;
+use itertools::Itertools as _;
+use log::{info, warn};
 use rand::Rng as _;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::fs::rename;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FullPiece {
     pub piece: PieceEnum,
     /// An optional comment to clarify the use of the piece
@@ -25,6 +30,15 @@ pub struct FullPiece {
     /// `Some` if this piece should be executed just once (so not on new machines)
     /// The machines to do it on if `one_time` is true
     one_time_todo_on: Option<Vec<Machine>>,
+    /// `Some` if this piece should only be executed on some machines, based on their tags
+    #[serde(default)]
+    target: Option<Target>,
+    /// The pieces that should be executed before this one (and undone after it)
+    #[serde(default)]
+    after: Vec<u32>,
+    /// The machines on which this piece was already satisfied, so it was marked as done without executing it
+    #[serde(default)]
+    satisfied_on: Vec<Machine>,
 }
 
 #[derive(Debug, Clone)]
@@ -37,7 +51,6 @@ pub enum Todo {
 type IdPiecePair<'a> = (u32, &'a mut FullPiece);
 
 impl FullPiece {
-    // TODO(low): one-time support (in all below methods), and then in cli
     pub const fn new(piece: PieceEnum, comment: Option<String>) -> Self {
         Self {
             piece,
@@ -45,10 +58,39 @@ impl FullPiece {
             done_on: vec![],
             undone_on: None,
             one_time_todo_on: None,
+            target: None,
+            after: vec![],
+            satisfied_on: vec![],
         }
     }
 
-    fn todo(&self, machine: &Machine) -> Todo {
+    /// Returns true if the tags of the machine match the target of the piece
+    fn targets(&self, machine: &Machine, machines: &IndexMap<Machine, MachineData>) -> bool {
+        self.target.as_ref().is_none_or(|target| {
+            target.matches(machines.get(machine).map_or(&[], MachineData::tags))
+        })
+    }
+
+    /// Only the machines that this piece targets
+    fn targeted(&self, on: &[Machine], machines: &IndexMap<Machine, MachineData>) -> Vec<Machine> {
+        on.iter()
+            .filter(|machine| self.targets(machine, machines))
+            .copied()
+            .collect()
+    }
+
+    fn todo(&self, machine: &Machine, machines: &IndexMap<Machine, MachineData>) -> Todo {
+        if !self.targets(machine, machines) {
+            return Todo::Noop;
+        }
+
+        if let Some(one_time_todo_on) = &self.one_time_todo_on
+            && !one_time_todo_on.contains(machine)
+        {
+            // Added after this one-time piece, so it should never be executed here
+            return Todo::Noop;
+        }
+
         let done = self.done_on.contains(machine);
         // `Some` if undo, contains `true` if it was undone on this machine
         let undone = self
@@ -71,13 +113,14 @@ impl FullPiece {
 
     pub fn get_todo<'a>(
         pieces: &'a mut IndexMap<u32, Self>,
+        machines: &IndexMap<Machine, MachineData>,
         machine: &Machine,
     ) -> (Vec<IdPiecePair<'a>>, Vec<IdPiecePair<'a>>) {
         let mut to_execute = vec![];
         let mut to_undo = vec![];
 
         for (&id, piece) in pieces {
-            match piece.todo(machine) {
+            match piece.todo(machine, machines) {
                 Todo::Noop => {}
                 Todo::Execute => to_execute.push((id, piece)),
                 Todo::Undo => to_undo.push((id, piece)),
@@ -87,67 +130,301 @@ impl FullPiece {
         (to_execute, to_undo)
     }
 
+    /// Execute and undo the pieces that are out of sync on this machine.
+    /// With `keep_going`, a failing piece doesn't stop the rest; the failures are in the report.
     pub fn do_todo(
         pieces: &mut IndexMap<u32, Self>,
+        machines: &IndexMap<Machine, MachineData>,
         machine: &Machine,
         execution_data: &ExecutionData,
-    ) -> Result<()> {
-        let (mut to_execute, mut to_undo) = Self::get_todo(pieces, machine);
-
-        PieceEnum::execute_bulk(
-            to_execute
-                .iter_mut()
-                .map(|(id, x)| {
-                    (*id, &mut x.piece, || {
-                        x.done_on.push(*machine);
-                    })
-                })
-                .collect(),
-            execution_data,
-        )?;
-
-        PieceEnum::undo_bulk(
-            to_undo
-                .iter_mut()
-                .map(|(id, x)| {
-                    (*id, &mut x.piece, || {
-                        // SAFETY: since we got `Todo::Undo` back we can assume that `piece.undone_one.is_some()`
-                        #[expect(clippy::missing_panics_doc, reason = "code path")]
-                        x.undone_on.as_mut().unwrap().push(*machine);
-                    })
-                })
-                .collect(),
-            execution_data,
-        )?;
+        keep_going: bool,
+    ) -> Result<Report> {
+        let (to_execute, to_undo) = Self::get_todo(pieces, machines, machine);
+        let mut report = Report::default();
+
+        let mut unsatisfied = vec![];
+        for (id, piece) in to_execute {
+            let satisfied = if execution_data.test_run {
+                Ok(false)
+            } else {
+                piece.piece.is_satisfied(execution_data)
+            };
+            match satisfied {
+                Ok(true) => {
+                    info!(
+                        "Piece is already satisfied, marking as done without executing: {} {}",
+                        print_id(id),
+                        piece.piece
+                    );
+                    piece.done_on.push(*machine);
+                    piece.satisfied_on.push(*machine);
+                    report.push(id, piece.piece.to_string(), false, Outcome::Satisfied);
+                }
+                Ok(false) => unsatisfied.push((id, piece)),
+                Err(err) if keep_going => {
+                    warn!("Failed to check piece {}: {err}", print_id(id));
+                    report.push(
+                        id,
+                        piece.piece.to_string(),
+                        false,
+                        Outcome::Failed(format!("Failed to check: {err}")),
+                    );
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        for batch in Self::batches(unsatisfied)? {
+            // Skip pieces that depend on a piece that failed or was skipped
+            let (skipped, batch): (Vec<_>, Vec<_>) = batch
+                .into_iter()
+                .partition(|(_, piece)| piece.after.iter().any(|dep| report.blocks(*dep)));
+            for (id, piece) in skipped {
+                report.push(id, piece.piece.to_string(), false, Outcome::Skipped);
+            }
+
+            Self::run_batch(batch, keep_going, false, &mut report, |batch| {
+                PieceEnum::execute_bulk(
+                    batch
+                        .iter_mut()
+                        .map(|(id, x)| {
+                            (*id, &mut x.piece, || {
+                                x.done_on.push(*machine);
+                            })
+                        })
+                        .collect(),
+                    execution_data,
+                )
+            })?;
+        }
+
+        // Undo in reverse order, so pieces are undone before the pieces they depend on
+        let mut blocked_after = vec![];
+        for batch in Self::batches(to_undo)?.into_iter().rev() {
+            // Skip pieces that a piece that failed or was skipped depends on
+            let (skipped, batch): (Vec<_>, Vec<_>) = batch
+                .into_iter()
+                .partition(|(id, _)| blocked_after.contains(id));
+            for (id, piece) in skipped {
+                report.push(id, piece.piece.to_string(), true, Outcome::Skipped);
+            }
+
+            let afters = batch
+                .iter()
+                .map(|(id, piece)| (*id, piece.after.clone()))
+                .collect::<Vec<_>>();
+            Self::run_batch(batch, keep_going, true, &mut report, |batch| {
+                PieceEnum::undo_bulk(
+                    batch
+                        .iter_mut()
+                        .map(|(id, x)| {
+                            (*id, &mut x.piece, || {
+                                // SAFETY: since we got `Todo::Undo` back we can assume that `piece.undone_one.is_some()`
+                                #[expect(clippy::missing_panics_doc, reason = "code path")]
+                                x.undone_on.as_mut().unwrap().push(*machine);
+                            })
+                        })
+                        .collect(),
+                    execution_data,
+                )
+            })?;
+            for (id, after) in afters {
+                if report.blocks(id) {
+                    blocked_after.extend(after);
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Run a batch of pieces (with `run`) and report the outcome.
+    /// The bulk pieces are run together and the non-bulk pieces one by one, so a piece that is
+    /// deferred (see `Deferred`) doesn't stop the rest. With `keep_going`, the bulk pieces are
+    /// retried one by one if running them together fails, and failures are reported instead of
+    /// returned.
+    fn run_batch<'a, F>(
+        batch: Vec<IdPiecePair<'a>>,
+        keep_going: bool,
+        undo: bool,
+        report: &mut Report,
+        mut run: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&mut [IdPiecePair<'a>]) -> Result<()>,
+    {
+        let displayed = |batch: &[IdPiecePair<'a>]| {
+            batch
+                .iter()
+                .map(|(id, piece)| (*id, piece.piece.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let (mut bulk, non_bulk): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .partition(|(_, piece)| matches!(piece.piece, PieceEnum::Bulk(_)));
+
+        let mut one_by_one = non_bulk;
+        if bulk.len() > 1 {
+            match run(&mut bulk) {
+                Ok(()) => {
+                    for (id, piece) in displayed(&bulk) {
+                        report.push(id, piece, undo, Outcome::Succeeded);
+                    }
+                }
+                Err(err) if keep_going => {
+                    warn!("Running pieces in bulk failed, retrying them one by one: {err}");
+                    one_by_one.splice(0..0, bulk);
+                }
+                Err(err) => return Err(err),
+            }
+        } else {
+            one_by_one.splice(0..0, bulk);
+        }
+
+        for piece in one_by_one {
+            let mut single = [piece];
+            let outcome = match run(&mut single) {
+                Ok(()) => Outcome::Succeeded,
+                Err(err) => {
+                    if let Some(Deferred(reason)) = err.downcast_ref::<Deferred>() {
+                        warn!(
+                            "Piece {} was deferred, it's left pending: {reason}",
+                            print_id(single[0].0)
+                        );
+                        Outcome::Deferred(reason.clone())
+                    } else if keep_going {
+                        warn!("Piece {} failed: {err}", print_id(single[0].0));
+                        Outcome::Failed(err.to_string())
+                    } else {
+                        return Err(err);
+                    }
+                }
+            };
+            let [(id, piece)] = single;
+            report.push(id, piece.piece.to_string(), undo, outcome);
+        }
 
         Ok(())
     }
 
-    pub fn add(args: &add::Args, execution_data: &ExecutionData) -> Result<(u32, Self)> {
+    /// Split pieces into batches that can be executed in order, so that every piece comes after
+    /// the pieces it depends on. Pieces within a batch don't depend on each other, so they can
+    /// be executed in bulk. Dependencies that aren't in `pieces` are already satisfied.
+    fn batches(pieces: Vec<IdPiecePair<'_>>) -> Result<Vec<Vec<IdPiecePair<'_>>>> {
+        let ids = pieces.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        let mut finished = vec![];
+        let mut batches = vec![];
+        let mut remaining = pieces;
+
+        while !remaining.is_empty() {
+            let (ready, blocked): (Vec<_>, Vec<_>) =
+                remaining.into_iter().partition(|(_, piece)| {
+                    piece
+                        .after
+                        .iter()
+                        .all(|dep| !ids.contains(dep) || finished.contains(dep))
+                });
+            if ready.is_empty() {
+                return Err(eyre!(
+                    "Dependency cycle between pieces {}",
+                    blocked.iter().map(|(id, _)| print_id(*id)).join(", ")
+                ));
+            }
+            finished.extend(ready.iter().map(|(id, _)| *id));
+            batches.push(ready);
+            remaining = blocked;
+        }
+
+        Ok(batches)
+    }
+
+    /// `machines`: all existing machines, for one-time pieces and targeting
+    /// `after`: the resolved ids of `args.after`
+    pub fn add(
+        args: &add::Args,
+        execution_data: &ExecutionData,
+        machines: &IndexMap<Machine, MachineData>,
+        after: Vec<u32>,
+    ) -> Result<(u32, Self)> {
         let mut piece = Self::from_cli(args)?;
         let id = Self::new_id();
 
+        if args.once {
+            piece.one_time_todo_on = Some(machines.keys().copied().collect());
+        }
+        piece.target = Target::from_cli(args);
+        piece.after = after;
+
         let is_file = piece.file().is_some();
+        let targets_here = piece.targets(&execution_data.machine, machines);
 
         let mut cb = || {
             piece.done_on.push(execution_data.machine);
         };
 
-        if args.undo.is_some()
+        if (args.undo.is_some() || args.check.is_some())
             && !matches!(
                 piece.piece,
                 PieceEnum::NonBulk(NonBulkPieceEnum::Command(_))
             )
         {
             return Err(eyre!(
-                "`--undo` only makes sense with a command piece. Autodetected pieces supply their own undo."
+                "`--undo` and `--check` only make sense with a command piece. Autodetected pieces supply their own."
             ));
         }
 
+        if (args.mode.is_some()
+            || args.sudo
+            || args.adopt
+            || args.expect_content.is_some()
+            || args.expect_distro_default)
+            && !is_file
+        {
+            return Err(eyre!(
+                "`--mode`, `--sudo`, `--adopt`, `--expect-content`, and `--expect-distro-default` only make sense with a file piece."
+            ));
+        }
+
+        if args.adopt
+            && let PieceEnum::NonBulk(NonBulkPieceEnum::File(file)) = &piece.piece
+            && let Some((file, repository)) = file.adopted_repository()
+        {
+            warn!(
+                "The symlink points to {}, which is in the git repository at {}. Remove it from there once the migration is done.",
+                file.display(),
+                repository.display()
+            );
+            if !execution_data.dry_run
+                && execution_data
+                    .input
+                    .confirm("Do you want to note the migration in the comment of the piece?")?
+            {
+                let note = format!("Migrated from {}", file.display());
+                piece.comment = Some(match piece.comment.take() {
+                    Some(comment) => format!("{comment} ({note})"),
+                    None => note,
+                });
+            }
+        }
+
         if args.not_done_here && is_file {
             return Err(eyre!(
                 "The concept of '--not-done-here' is incompatible with file pieces. Adding a file piece performs a special action."
             ));
+        } else if !targets_here && is_file {
+            return Err(eyre!(
+                "A file piece can only be added on a machine it targets, since adding it moves the file into the repo."
+            ));
+        } else if !targets_here {
+            info!("Piece doesn't target this machine, so it's not executed here");
+        } else if args.not_done_here
+            && !execution_data.test_run
+            && piece.piece.is_satisfied(execution_data)?
+        {
+            info!("Piece is already satisfied here, marking as done without executing");
+            piece.satisfied_on.push(execution_data.machine);
+            cb();
         } else if args.not_done_here || is_file {
             // We could bypass `execute_bulk` here, but this is clearer
             PieceEnum::execute_bulk(vec![(id, &mut piece.piece, cb)], execution_data)?;
@@ -169,6 +446,15 @@ impl FullPiece {
             return Err(eyre!("This piece is already undone"));
         }
 
+        if let Some(strategy) = args.file_undo_strategy() {
+            let PieceEnum::NonBulk(NonBulkPieceEnum::File(file)) = &mut self.piece else {
+                return Err(eyre!(
+                    "`--materialize`, `--restore`, and `--delete` only make sense with a file piece."
+                ));
+            };
+            file.set_undo_strategy(strategy);
+        }
+
         let mut cb = || {
             self.undone_on = Some(vec![execution_data.machine]);
         };
@@ -184,21 +470,150 @@ impl FullPiece {
         Ok(())
     }
 
-    /// Returns true if the piece is safe to clean up
-    pub fn unused(&self) -> bool {
+    /// Three-way merge of a piece that is present on both sides.
+    /// Progress (the machines it's done and undone on) is combined, keeping machines that were
+    /// removed on one side (by a reset or a move) removed. If the definition of the piece was
+    /// changed differently on both sides, that's a conflict.
+    pub fn merge(id: u32, base: Option<&Self>, ours: Self, theirs: Self) -> Result<Self> {
+        let conflict = |what: &str| {
+            eyre!(
+                "Conflict: the {what} of piece {} was changed differently on this machine and on the remote",
+                print_id(id)
+            )
+        };
+
+        Ok(Self {
+            piece: merge_value(base.map(|b| &b.piece), ours.piece, theirs.piece)
+                .ok_or_else(|| conflict("value"))?,
+            comment: merge_value(base.map(|b| &b.comment), ours.comment, theirs.comment)
+                .ok_or_else(|| conflict("comment"))?,
+            done_on: merge_set(base.map(|b| &b.done_on[..]), ours.done_on, theirs.done_on),
+            undone_on: match (ours.undone_on, theirs.undone_on) {
+                (Some(ours), Some(theirs)) => Some(merge_set(
+                    base.and_then(|b| b.undone_on.as_deref()),
+                    ours,
+                    theirs,
+                )),
+                (Some(undone_on), None) | (None, Some(undone_on)) => Some(undone_on),
+                (None, None) => None,
+            },
+            one_time_todo_on: merge_value(
+                base.map(|b| &b.one_time_todo_on),
+                ours.one_time_todo_on,
+                theirs.one_time_todo_on,
+            )
+            .ok_or_else(|| conflict("one-time machines"))?,
+            target: merge_value(base.map(|b| &b.target), ours.target, theirs.target)
+                .ok_or_else(|| conflict("target"))?,
+            after: merge_value(base.map(|b| &b.after), ours.after, theirs.after)
+                .ok_or_else(|| conflict("dependencies"))?,
+            satisfied_on: merge_set(
+                base.map(|b| &b.satisfied_on[..]),
+                ours.satisfied_on,
+                theirs.satisfied_on,
+            ),
+        })
+    }
+
+    /// Forget about the machines that don't satisfy the predicate
+    pub fn retain_machines<F: Fn(&Machine) -> bool>(&mut self, f: F) {
+        self.done_on.retain(&f);
+        if let Some(undone_on) = &mut self.undone_on {
+            undone_on.retain(&f);
+        }
+        if let Some(one_time_todo_on) = &mut self.one_time_todo_on {
+            one_time_todo_on.retain(&f);
+        }
+        self.satisfied_on.retain(&f);
+    }
+
+    /// Move a file piece to a new location: in the repo, and on this machine if it's done here.
+    /// It's no longer done on the other machines, so they move it when they sync.
+    /// Undone pieces can't be moved, since machines that undid it would have to do it again.
+    /// Returns the old and new locations, relative to the file dir.
+    pub fn move_file(
+        &mut self,
+        id: u32,
+        location: PathBuf,
+        execution_data: &ExecutionData,
+    ) -> Result<(PathBuf, PathBuf)> {
+        let PieceEnum::NonBulk(NonBulkPieceEnum::File(file)) = &mut self.piece else {
+            return Err(eyre!("Only file pieces can be moved."));
+        };
+        if self.undone_on.is_some() {
+            return Err(eyre!("Undone pieces can't be moved."));
+        }
+        let old = file.relative_location().to_path_buf();
+        file.move_to(location)?;
+        let new = file.relative_location().to_path_buf();
+
+        let old_target = execution_data.file_dir.join(&old);
+        let new_target = execution_data.file_dir.join(&new);
+        if new_target.symlink_metadata().is_ok() {
+            return Err(eyre!("{} already exists in the repo", new_target.display()));
+        }
+        if execution_data.dry_run {
+            info!(
+                "Dry run! Would move {} to {}",
+                old_target.display(),
+                new_target.display()
+            );
+        } else {
+            create_parent(&new_target)?;
+            rename(&old_target, &new_target).wrap_err("Failed to move file in repo")?;
+        }
+
+        let done_here = self.done_on.contains(&execution_data.machine);
+        self.done_on.clear();
+        self.satisfied_on.clear();
+        if done_here {
+            if execution_data.dry_run {
+                info!("Dry run! Would move the file on this machine");
+            } else {
+                let cb = || self.done_on.push(execution_data.machine);
+                PieceEnum::execute_bulk(vec![(id, &mut self.piece, cb)], execution_data)?;
+            }
+        }
+
+        Ok((old, new))
+    }
+
+    /// Forget whether this piece was done or undone on a machine
+    pub fn reset_machine(&mut self, machine: &Machine) {
+        self.done_on.retain(|m| m != machine);
+        if let Some(undone_on) = &mut self.undone_on {
+            undone_on.retain(|m| m != machine);
+        }
+        self.satisfied_on.retain(|m| m != machine);
+    }
+
+    /// Returns true if the piece was marked as done on the machine because it was already satisfied
+    pub fn satisfied_on(&self, machine: &Machine) -> bool {
+        self.satisfied_on.contains(machine)
+    }
+
+    /// Returns true if the piece is safe to clean up.
+    /// Machines that the piece doesn't target are not considered.
+    pub fn unused(&self, machines: &IndexMap<Machine, MachineData>) -> bool {
         #[expect(clippy::option_if_let_else)]
         if let Some(undone_on) = &self.undone_on {
             // If it's something to undo (whether it's one_time or not),
             //  we don't want to execute it on new machines and can remove it
             //  if none of our existing machines need to have it undone
 
-            set_eq(&self.done_on, undone_on)
+            set_eq(
+                &self.targeted(&self.done_on, machines),
+                &self.targeted(undone_on, machines),
+            )
         } else if let Some(one_time_todo_on) = &self.one_time_todo_on {
             // We do not want to check with a list of all machines here, since
             //  new machines that are added since the addition of the
             //  one_time piece should not have the piece executed on them.
 
-            set_eq(&self.done_on, one_time_todo_on)
+            set_eq(
+                &self.targeted(&self.done_on, machines),
+                &self.targeted(one_time_todo_on, machines),
+            )
         } else {
             // Any non-undo and non-one_time pieces should never be cleaned up,
             //  since they need to be executed on new machines.
@@ -217,13 +632,24 @@ impl FullPiece {
     }
 
     /// Return information about this piece for printing in the console
-    pub fn print(&self, id: u32) -> String {
+    pub fn print(&self, id: u32, machines: &IndexMap<Machine, MachineData>) -> String {
         let id_prefix = print_id(id);
 
         let undo_suffix = if let PieceEnum::NonBulk(NonBulkPieceEnum::Command(piece)) = &self.piece
-            && let Some(undo_command) = &piece.undo_command
         {
-            format!(" (undo: {undo_command})")
+            let undo = piece
+                .undo_command
+                .as_ref()
+                .map_or_else(String::new, |undo_command| {
+                    format!(" (undo: {undo_command})")
+                });
+            let check = piece
+                .check_command
+                .as_ref()
+                .map_or_else(String::new, |check_command| {
+                    format!(" (check: {check_command})")
+                });
+            format!("{undo}{check}")
         } else {
             String::new()
         };
@@ -234,29 +660,123 @@ impl FullPiece {
             .as_ref()
             .map_or_else(String::new, |comment| format!(" // {comment}"));
 
-        let unused_suffix = if self.unused() { " (unused)" } else { "" };
+        // Not styled when empty, so it doesn't add escape codes to every line
+        let once_suffix = if self.one_time_todo_on.is_some() {
+            format!("{}", " (once)".bright_blue())
+        } else {
+            String::new()
+        };
+
+        let target_suffix = self.target.as_ref().map_or_else(String::new, |target| {
+            format!("{}", format!(" ({target})").bright_blue())
+        });
+
+        let after_suffix = if self.after.is_empty() {
+            String::new()
+        } else {
+            let after = self.after.iter().map(|id| format!("[{id:08x}]")).join(", ");
+            format!("{}", format!(" (after: {after})").bright_blue())
+        };
+
+        let unused_suffix = if self.unused(machines) {
+            " (unused)"
+        } else {
+            ""
+        };
         let unused_suffix = unused_suffix.italic();
         let unused_suffix = unused_suffix.bright_cyan();
 
         // TODO(low): Workaround for https://github.com/owo-colors/owo-colors/issues/45. Fix better.
         if self.undone_on.is_some() {
             format!(
-                "{}{}{}{}{}{}",
+                "{}{}{}{}{}{}{}{}{}",
                 id_prefix.strikethrough(),
                 " ".strikethrough(),
                 self.piece.strikethrough(),
                 undo_suffix.strikethrough(),
                 comment_suffix.strikethrough(),
+                once_suffix,
+                target_suffix,
+                after_suffix,
                 unused_suffix,
             )
         } else {
             format!(
-                "{} {}{}{}{}",
-                id_prefix, self.piece, undo_suffix, comment_suffix, unused_suffix,
+                "{} {}{}{}{}{}{}{}",
+                id_prefix,
+                self.piece,
+                undo_suffix,
+                comment_suffix,
+                once_suffix,
+                target_suffix,
+                after_suffix,
+                unused_suffix,
             )
         }
     }
 
+    /// Returns true if the piece is executed, and not undone, on the machine
+    fn deployed_on(&self, machine: &Machine) -> bool {
+        self.done_on.contains(machine)
+            && !self
+                .undone_on
+                .as_ref()
+                .is_some_and(|undone_on| undone_on.contains(machine))
+    }
+
+    /// Redeploy the hardlinks and copies of file pieces whose version in the repo changed
+    pub fn redeploy_files(
+        pieces: &IndexMap<u32, Self>,
+        machine: &Machine,
+        execution_data: &ExecutionData,
+    ) -> Result<()> {
+        for piece in pieces.values() {
+            if let PieceEnum::NonBulk(NonBulkPieceEnum::File(file)) = &piece.piece
+                && piece.deployed_on(machine)
+            {
+                match file.redeploy(execution_data) {
+                    Err(err) if err.downcast_ref::<Deferred>().is_some() => {
+                        warn!("Not redeploying: {file}: {err}");
+                    }
+                    result => result?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy local edits of the hardlinks and copies of file pieces back into the repo,
+    /// and record permission changes. Returns descriptions of the permission changes.
+    pub fn collect_local_edits(
+        pieces: &mut IndexMap<u32, Self>,
+        machine: &Machine,
+        execution_data: &ExecutionData,
+    ) -> Result<Vec<String>> {
+        Self::collect_local_edits_of(pieces, machine, execution_data, |_| true)
+    }
+
+    /// Like `collect_local_edits`, but only for the file pieces whose file (relative to the file
+    /// dir) is selected
+    pub fn collect_local_edits_of<F: Fn(&Path) -> bool>(
+        pieces: &mut IndexMap<u32, Self>,
+        machine: &Machine,
+        execution_data: &ExecutionData,
+        selected: F,
+    ) -> Result<Vec<String>> {
+        let mut metadata_changes = vec![];
+        for piece in pieces.values_mut() {
+            let deployed = piece.deployed_on(machine);
+            if let PieceEnum::NonBulk(NonBulkPieceEnum::File(file)) = &mut piece.piece
+                && deployed
+                && selected(file.relative_location())
+            {
+                file.collect_local_edits(execution_data)?;
+                metadata_changes.extend(file.collect_metadata());
+            }
+        }
+        Ok(metadata_changes)
+    }
+
     /// If this is a file piece, get the filename relative to the file dir
     pub fn file(&self) -> Option<&Path> {
         if let PieceEnum::NonBulk(NonBulkPieceEnum::File(file)) = &self.piece {
@@ -4,11 +4,20 @@ use crate::machine::{Machine, MachineData};
 use crate::repo::Repo;
 use color_eyre::Result;
 use color_eyre::eyre::{WrapErr as _, eyre};
-use log::{debug, info};
+use log::{debug, info, warn};
 use std::fs;
 use std::fs::remove_dir_all;
 use std::path::{Path, PathBuf};
 
+/// Take over the identity of a machine that is already in the repo, for example after reinstalling
+#[derive(Debug)]
+pub struct Reclaim {
+    /// The machine id (or a prefix of it), name, or hostname
+    pub machine: String,
+    /// Forget what was done on the machine, so everything is executed again
+    pub reset: bool,
+}
+
 #[derive(Debug)]
 pub struct Installation {
     machine: Machine,
@@ -28,8 +37,13 @@ impl Installation {
         &mut self.repo
     }
 
-    pub fn init(top_level_args: &TopLevelArgs, remote: &str, new: bool) -> Result<()> {
-        match Self::_init(top_level_args, remote, new) {
+    pub fn init(
+        top_level_args: &TopLevelArgs,
+        remote: &str,
+        new: bool,
+        reclaim: Option<Reclaim>,
+    ) -> Result<()> {
+        match Self::_init(top_level_args, remote, new, reclaim) {
             Ok(()) => Ok(()),
             Err(e) => {
                 info!(
@@ -41,7 +55,12 @@ impl Installation {
         }
     }
 
-    fn _init(top_level_args: &TopLevelArgs, remote: &str, new: bool) -> Result<()> {
+    fn _init(
+        top_level_args: &TopLevelArgs,
+        remote: &str,
+        new: bool,
+        reclaim: Option<Reclaim>,
+    ) -> Result<()> {
         let root = &top_level_args.path;
         debug!("Looking at {}", root.display());
 
@@ -53,11 +72,26 @@ impl Installation {
         let machine_path = root.join("machine");
         let repository_path = Self::get_repository_path(root);
 
-        let machine = Machine::new();
+        let (mut repo, files) = Repo::init(remote, &repository_path, new, &top_level_args.config)?;
+
+        let data = repo.data_mut();
+        let machine = if let Some(reclaim) = reclaim {
+            let machine = data.find_machine(&reclaim.machine)?;
+            if reclaim.reset {
+                data.reset_machine(&machine);
+            }
+            info!("Reclaimed machine {}", machine.0);
+            machine
+        } else {
+            let machine = Machine::new();
+            data.machines_mut()
+                .insert(machine, MachineData::new_this()?);
+            machine
+        };
         fs::write(&machine_path, machine.0.to_string())?;
-        let machine_data = MachineData::new_this()?;
 
-        Repo::init(remote, &repository_path, machine, machine_data, new)?;
+        repo.write_and_push(files)
+            .wrap_err("Failed to write_and_push")?;
 
         Ok(())
     }
@@ -78,36 +112,69 @@ impl Installation {
                 .wrap_err("`machine` file does not contain a valid UUID".to_owned())?,
         );
 
-        let repo = Repo::get_from_path(&Self::get_repository_path(root))?;
+        let repo = Repo::get_from_path(
+            &Self::get_repository_path(root),
+            top_level_args.dry_run,
+            &top_level_args.config,
+        )?;
 
         Ok(Self { machine, repo })
     }
 
-    fn get_repository_path(root: &Path) -> PathBuf {
+    pub fn get_repository_path(root: &Path) -> PathBuf {
         root.join("repository")
     }
 
-    fn check_synced(&mut self) {
-        let (to_execute, to_undo) =
-            FullPiece::get_todo(self.repo.data_mut().pieces_mut(), &self.machine);
+    pub fn get_backup_dir(root: &Path) -> PathBuf {
+        root.join("backups")
+    }
+
+    pub fn get_deployed_dir(root: &Path) -> PathBuf {
+        root.join("deployed")
+    }
+
+    pub fn check_synced(&mut self) {
+        let (pieces, machines) = self.repo.data_mut().pieces_mut_and_machines();
+        let (to_execute, to_undo) = FullPiece::get_todo(pieces, machines, &self.machine);
 
         if !to_execute.is_empty() || !to_undo.is_empty() {
             info!(
                 "You have changes on the remote that are not executed locally! Use `falconf sync` to execute them. Unsynced changes:"
             );
             for (id, piece) in to_execute {
-                info!("- Execute: {}", piece.print(id));
+                info!("- Execute: {}", piece.print(id, machines));
             }
             for (id, piece) in to_undo {
-                info!("- Undo: {}", piece.print(id));
+                info!("- Undo: {}", piece.print(id, machines));
             }
         }
     }
 
+    /// Warn about tracked files with local changes, as nobody notices them until they push.
+    /// This is only a reminder, so failing to check doesn't fail the command.
+    pub fn check_local_changes(&self) {
+        match self.repo.changed_files() {
+            Ok(changed) if !changed.is_empty() => warn!(
+                "{} tracked file(s) have local changes, run `falconf push` to push them",
+                changed.len()
+            ),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to check for local changes: {e}"),
+        }
+    }
+
+    /// `check_synced`: remind the user of remote changes that aren't executed, and local changes
+    /// that aren't pushed
     pub fn pull_and_read(&mut self, check_synced: bool) -> Result<()> {
         self.repo.pull_and_read()?;
+        if !self.repo.data().machines().contains_key(&self.machine) {
+            return Err(eyre!(
+                "This machine is not in the repo; it was probably retired. Remove the falconf directory and run `falconf init` to add it again."
+            ));
+        }
         if check_synced {
             self.check_synced();
+            self.check_local_changes();
         }
         Ok(())
     }
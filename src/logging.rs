@@ -33,6 +33,11 @@ fn log_execution(command: &Command) {
     info!("Running command: `{}`", as_string(command));
 }
 
+/// Report a command that would run if this wasn't a dry run
+pub fn log_dry_run(command: &Command) {
+    info!("Dry run! Would run command: `{}`", as_string(command));
+}
+
 fn as_string(command: &Command) -> String {
     shell_words::join(
         iter::once(command.get_program().to_string_lossy().to_string()).chain(
@@ -1,7 +1,12 @@
+use crate::utils::merge_value;
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
+/// How old the recorded last sync may get before a sync that changes nothing records it again
+const LAST_SYNC_INTERVAL: u64 = 24 * 60 * 60;
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct Machine(pub Uuid);
 
@@ -11,15 +16,78 @@ impl Machine {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MachineData {
     hostname: String,
+    /// A name given with `falconf machine rename`, shown instead of the hostname
+    #[serde(default)]
+    name: Option<String>,
+    /// Tags to target pieces at groups of machines, like `laptop` or `server`
+    #[serde(default)]
+    tags: Vec<String>,
+    /// When this machine last synced, in seconds since the Unix epoch
+    #[serde(default)]
+    last_sync: Option<u64>,
 }
 
 impl MachineData {
     pub fn new_this() -> Result<Self> {
         Ok(Self {
             hostname: hostname::get()?.to_string_lossy().into_owned(),
+            name: None,
+            tags: vec![],
+            last_sync: None,
         })
     }
+
+    /// Three-way merge of a machine that is present on both sides.
+    /// Returns `None` if it was changed differently on both sides.
+    pub fn merge(base: Option<&Self>, ours: Self, theirs: Self) -> Option<Self> {
+        Some(Self {
+            hostname: merge_value(base.map(|b| &b.hostname), ours.hostname, theirs.hostname)?,
+            name: merge_value(base.map(|b| &b.name), ours.name, theirs.name)?,
+            tags: merge_value(base.map(|b| &b.tags), ours.tags, theirs.tags)?,
+            // Only the machine itself syncs, so just take the latest
+            last_sync: ours.last_sync.max(theirs.last_sync),
+        })
+    }
+
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.hostname)
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = Some(name);
+    }
+
+    pub const fn last_sync(&self) -> Option<u64> {
+        self.last_sync
+    }
+
+    /// Record a sync. If it didn't change anything (`changed`), it's only recorded when the
+    /// recorded one is older than `LAST_SYNC_INTERVAL`, so regular syncs (from a timer, for
+    /// example) don't commit every time.
+    pub fn update_last_sync(&mut self, changed: bool) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        if changed
+            || self
+                .last_sync
+                .is_none_or(|last| now.saturating_sub(last) >= LAST_SYNC_INTERVAL)
+        {
+            self.last_sync = Some(now);
+        }
+        Ok(())
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub const fn tags_mut(&mut self) -> &mut Vec<String> {
+        &mut self.tags
+    }
 }
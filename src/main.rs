@@ -6,9 +6,13 @@
 #![cfg_attr(test, allow(clippy::missing_panics_doc))]
 
 use color_eyre::eyre;
+use std::process::ExitCode;
 
 mod cli;
+mod config;
+mod conflict;
 mod data;
+mod diff;
 mod execution_data;
 mod full_piece;
 mod installation;
@@ -17,11 +21,13 @@ mod machine;
 mod piece;
 mod pieces;
 mod repo;
+mod report;
+mod target;
 #[cfg(test)]
 mod testing;
 mod utils;
 
-fn main() -> Result<(), eyre::Report> {
+fn main() -> Result<ExitCode, eyre::Report> {
     color_eyre::config::HookBuilder::new()
         .display_location_section(true)
         .install()?;
@@ -9,6 +9,9 @@ pub trait NonBulkPiece: Display {
 
     /// Undo a single piece.
     fn undo(&mut self, execution_data: &ExecutionData) -> Result<()>;
+
+    /// Check if the piece is already satisfied on this machine, so executing it can be skipped.
+    fn is_satisfied(&self, execution_data: &ExecutionData) -> Result<bool>;
 }
 
 /// A single piece of configuration (bulk)
@@ -18,4 +21,7 @@ pub trait BulkPiece: Display {
 
     /// Undo multiple of these pieces in bulk.
     fn undo_bulk(pieces: &[&mut Self], execution_data: &ExecutionData) -> Result<()>;
+
+    /// Check if a single piece is already satisfied on this machine, so executing it can be skipped.
+    fn is_satisfied(&self, execution_data: &ExecutionData) -> Result<bool>;
 }
@@ -1,35 +1,56 @@
 use crate::cli::add;
 use crate::execution_data::ExecutionData;
-use crate::logging::CommandExt as _;
+use crate::logging::{CommandExt as _, log_dry_run};
 use crate::piece::BulkPiece;
 use color_eyre::Result;
-use color_eyre::eyre::eyre;
+use color_eyre::eyre::{OptionExt as _, eyre};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 use std::process;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Apt {
     /// The package to install
     package: String,
 }
 
 impl BulkPiece for Apt {
-    fn execute_bulk(pieces: &[&mut Self], _execution_data: &ExecutionData) -> Result<()> {
-        Self::apt_command(&["install"], pieces)
+    fn execute_bulk(pieces: &[&mut Self], execution_data: &ExecutionData) -> Result<()> {
+        Self::apt_command(&["install"], pieces, execution_data)
     }
 
-    fn undo_bulk(pieces: &[&mut Self], _execution_data: &ExecutionData) -> Result<()> {
-        Self::apt_command(&["remove", "--autoremove"], pieces)
+    fn undo_bulk(pieces: &[&mut Self], execution_data: &ExecutionData) -> Result<()> {
+        Self::apt_command(&["remove", "--autoremove"], pieces, execution_data)
+    }
+
+    fn is_satisfied(&self, _execution_data: &ExecutionData) -> Result<bool> {
+        let output = process::Command::new("dpkg-query")
+            .args(["--show", "--showformat=${Status}", &self.package])
+            .output_fallible()?;
+        // Fails if the package is unknown
+        Ok(output.status.success() && output.stdout == b"install ok installed")
     }
 }
 
 impl Apt {
-    fn apt_command(command: &[&str], pieces: &[&mut Self]) -> Result<()> {
-        process::Command::new("apt")
+    fn apt_command(
+        command: &[&str],
+        pieces: &[&mut Self],
+        execution_data: &ExecutionData,
+    ) -> Result<()> {
+        let (program, args) = execution_data
+            .apt
+            .split_first()
+            .ok_or_eyre("The apt command is empty")?;
+        let mut apt = process::Command::new(program);
+        apt.args(args)
             .args(command)
-            .args(pieces.iter().map(|p| &p.package))
-            .status_checked()?;
+            .args(pieces.iter().map(|p| &p.package));
+        if execution_data.dry_run {
+            log_dry_run(&apt);
+        } else {
+            apt.status_checked()?;
+        }
         Ok(())
     }
 
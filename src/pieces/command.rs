@@ -1,44 +1,83 @@
 use crate::cli::add;
 use crate::execution_data::ExecutionData;
-use crate::logging::CommandExt as _;
+use crate::logging::{CommandExt as _, log_dry_run};
 use crate::piece::NonBulkPiece;
+use crate::report::Deferred;
 use crate::utils::prompt;
 use color_eyre::Result;
+use color_eyre::eyre::OptionExt as _;
+use log::info;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 use std::process;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Command {
     /// The command to run
     command: String,
     // TODO(test): test the undo_command
     /// The command to run when undoing
     pub undo_command: Option<String>,
+    /// A command that succeeds if the piece is already satisfied, so it doesn't have to run
+    #[serde(default)]
+    pub check_command: Option<String>,
 }
 
 impl NonBulkPiece for Command {
-    fn execute(&mut self, _execution_data: &ExecutionData) -> Result<()> {
-        Self::run_command(&self.command)
+    fn execute(&mut self, execution_data: &ExecutionData) -> Result<()> {
+        Self::run_command(&self.command, execution_data)
     }
 
-    fn undo(&mut self, _execution_data: &ExecutionData) -> Result<()> {
+    fn undo(&mut self, execution_data: &ExecutionData) -> Result<()> {
         if self.undo_command.is_none() {
+            if execution_data.dry_run {
+                info!(
+                    "Dry run! Would ask for an undo command, since this command piece is missing one"
+                );
+                return Ok(());
+            }
+            if !execution_data.input.is_interactive() {
+                return Err(Deferred(String::from(
+                    "This command piece is missing an undo command, which has to be asked for",
+                ))
+                .into());
+            }
             let undo_command =
                 prompt("This command piece is missing an undo command. Undo command to use: ")?;
             self.undo_command = Some(undo_command);
         }
         // TODO(low): do this in a non-unwrappy way
-        Self::run_command(self.undo_command.as_ref().unwrap())
+        Self::run_command(self.undo_command.as_ref().unwrap(), execution_data)
+    }
+
+    fn is_satisfied(&self, execution_data: &ExecutionData) -> Result<bool> {
+        let Some(check_command) = &self.check_command else {
+            return Ok(false);
+        };
+        let output = Self::shell(check_command, execution_data)?.output_fallible()?;
+        Ok(output.status.success())
     }
 }
 
 impl Command {
-    fn run_command(command: &str) -> Result<()> {
-        process::Command::new("bash")
-            .arg("-c")
-            .arg(command)
-            .status_checked()?;
+    /// Run `command` with the configured shell
+    fn shell(command: &str, execution_data: &ExecutionData) -> Result<process::Command> {
+        let (shell, args) = execution_data
+            .shell
+            .split_first()
+            .ok_or_eyre("The shell is empty")?;
+        let mut shell = process::Command::new(shell);
+        shell.args(args).arg("-c").arg(command);
+        Ok(shell)
+    }
+
+    fn run_command(command: &str, execution_data: &ExecutionData) -> Result<()> {
+        let mut shell = Self::shell(command, execution_data)?;
+        if execution_data.dry_run {
+            log_dry_run(&shell);
+        } else {
+            shell.status_checked()?;
+        }
         Ok(())
     }
 
@@ -46,6 +85,7 @@ impl Command {
         Self {
             command: Self::parse_value(&args.value),
             undo_command: args.undo.clone(),
+            check_command: args.check.clone(),
         }
     }
 
@@ -1,104 +1,332 @@
 use crate::cli::add;
+use crate::config::OnConflict;
+use crate::conflict;
 use crate::execution_data::ExecutionData;
-use crate::logging::CommandExt as _;
+use crate::logging::{CommandExt as _, log_dry_run};
 use crate::piece::NonBulkPiece;
-use crate::utils::{confirm, create_parent};
+use crate::report::Deferred;
+use crate::utils::{confirm, create_parent, if_sudo, read_file_or_stdin, same_content};
+use clap::ValueEnum;
 use color_eyre::Result;
 use color_eyre::eyre::{WrapErr as _, eyre};
-use log::{debug, info};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
-use std::fs::{remove_file, rename};
+use std::fs::{read_link, remove_file, rename};
+use std::os::unix::fs::MetadataExt as _;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::{fs, io};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct File {
     /// The location the file should be linked to
     location: PathBuf,
-    // TODO(med): Add hardlink support
-    //  Check if hardlinks actually work when new versions of files are pulled with git.
-    //  See the Trello thing
-    // /// If the file should be a hardlink or symlink
-    // hardlink: bool,
-    // TODO(med): Add sudo support
-    // /// If the file should be created as root
-    // sudo: bool,
+    /// How the file in the repo is deployed to the location
+    #[serde(default)]
+    mode: LinkMode,
+    /// If this is a directory whose files are linked one by one (instead of linking the
+    /// directory itself), so files ignored by `.falconfignore` are left alone
+    #[serde(default)]
+    per_file: bool,
+    /// If the file is owned by root, so it's managed with root privileges
+    #[serde(default)]
+    sudo: bool,
+    /// The permissions (and ownership, for sudo files) of the file, restored when it's deployed
+    #[serde(default)]
+    metadata: Option<FileMetadata>,
     /// What the file should look like before the operation if it exists
-    expected_previous_content: Option<String>,
+    #[serde(with = "text_or_bytes")]
+    expected_previous_content: Option<Vec<u8>>,
+    /// If an existing symlink at the location (like one from GNU Stow) is replaced, instead of
+    /// refusing to overwrite it
+    #[serde(default)]
+    adopt: bool,
+    /// Where the file was before it was moved with `falconf mv`, oldest first, so other machines
+    /// can clean it up, even if they missed some of the moves
+    #[serde(default, deserialize_with = "deserialize_moved_from")]
+    moved_from: Vec<PathBuf>,
+    /// If the file should be the unmodified version installed by its package before the operation
+    /// if it exists, so stock files are overwritten but customized ones aren't
+    #[serde(default)]
+    expect_distro_default: bool,
+    /// What to do with the file when the piece is undone
+    #[serde(default)]
+    undo_strategy: UndoStrategy,
+}
+
+/// How the file in the repo is deployed to its location
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[value(rename_all = "kebab-case")]
+pub enum LinkMode {
+    /// A symlink to the file in the repo
+    #[default]
+    Symlink,
+    /// A hardlink to the file in the repo. Git replaces files instead of editing them, so the
+    /// file is relinked on sync when the repo version changes. Only for regular files.
+    Hardlink,
+    /// A copy of the file in the repo, for programs that don't follow symlinks. Local edits
+    /// are collected by `falconf push`, and the file is copied again on sync when the repo
+    /// version changes. Only for regular files.
+    Copy,
+}
+
+/// The name of the files with ignore patterns (in gitignore syntax) for directories
+pub const IGNORE_FILE: &str = ".falconfignore";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    /// The permission bits, like `0o644`
+    mode: u32,
+    /// Only recorded for sudo files
+    #[serde(default)]
+    owner: Option<Owner>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Owner {
+    uid: u32,
+    gid: u32,
+}
+
+impl FileMetadata {
+    fn of(path: &Path, sudo: bool) -> Result<Self> {
+        let metadata = path
+            .metadata()
+            .wrap_err_with(|| format!("Failed to get metadata of {}", path.display()))?;
+        Ok(Self {
+            mode: metadata.mode() & 0o7777,
+            owner: sudo.then(|| Owner {
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            }),
+        })
+    }
+}
+
+/// What to do with the file when a file piece is undone
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UndoStrategy {
+    /// Only remove the symlink
+    #[default]
+    Delete,
+    /// Replace the symlink with a copy of the content in the repo
+    Materialize,
+    /// Put back the content the file had before it was first linked on the machine
+    Restore,
 }
 
 impl NonBulkPiece for File {
     fn execute(&mut self, execution_data: &ExecutionData) -> Result<()> {
+        self.clean_up_moved_from(execution_data)?;
+        if self.per_file {
+            return self.execute_dir(execution_data);
+        }
         let target_file = self.target_file(execution_data);
 
-        if !target_file.exists() {
+        let newly_added = !target_file.exists();
+        if newly_added {
             info!("Repo (target) file doesn't exist, assuming this is newly added");
-            debug!(
-                "Moving the file into the repo: {} to {}",
-                self.location.display(),
-                target_file.display()
-            );
-            create_parent(&target_file)?;
-            rename(&self.location, &target_file).wrap_err("Failed to move file into repo")?;
+            if execution_data.dry_run {
+                info!(
+                    "Dry run! Would move the file into the repo: {} to {}",
+                    self.location.display(),
+                    target_file.display()
+                );
+            } else {
+                debug!(
+                    "Moving the file into the repo: {} to {}",
+                    self.location.display(),
+                    target_file.display()
+                );
+                create_parent(&target_file)?;
+                self.backup(execution_data)?;
+                if self.location.is_symlink() {
+                    // Adopting: copy the content the symlink points to, and replace the symlink
+                    fs::copy(&self.location, &target_file)
+                        .wrap_err("Failed to copy file into repo")?;
+                    self.remove_location(execution_data)?;
+                } else if self.sudo {
+                    self.copy_into_repo(&target_file, execution_data)?;
+                    self.remove_location(execution_data)?;
+                } else {
+                    rename(&self.location, &target_file)
+                        .wrap_err("Failed to move file into repo")?;
+                }
+            }
         }
 
-        if self.location.exists() {
-            if self.location.is_symlink() {
-                return Err(eyre!("File already exists and is a symlink."));
+        // During a dry run a newly added file wasn't actually moved, so it still exists
+        if self.location.exists() && !(newly_added && execution_data.dry_run) {
+            if self.location.is_symlink() && !self.adopt {
+                return Err(eyre!(
+                    "File already exists and is a symlink. Add the file with `--adopt` to replace it."
+                ));
             }
+            self.backup(execution_data)?;
 
             if let Some(expected_previous_content) = &self.expected_previous_content {
-                let actual_content = std::fs::read_to_string(&self.location)?;
+                let actual_content = self.read_location(execution_data)?;
                 if actual_content != *expected_previous_content {
                     return Err(eyre!(
-                        "File already exists and has different content than expected. Expected content: '{expected_previous_content}', actual content: '{actual_content}'."
+                        "File already exists and has different content than expected. Expected content: '{}', actual content: '{}'.",
+                        String::from_utf8_lossy(expected_previous_content),
+                        String::from_utf8_lossy(&actual_content)
                     ));
                 }
                 info!("File already exists but has expected content; overwriting.");
+            } else if self.expect_distro_default {
+                let actual = format!("{:x}", md5::compute(self.read_location(execution_data)?));
+                match distro_default_md5(&self.location)? {
+                    Some(md5) if md5 == actual => {
+                        info!("File already exists but is the distro default; overwriting.");
+                    }
+                    Some(_) => {
+                        return Err(eyre!(
+                            "File already exists and was customized (it's different from the distro default); not overwriting it. Remove the file and sync again to use the version in the repo."
+                        ));
+                    }
+                    None => {
+                        return Err(eyre!(
+                            "File already exists, but is not a conffile of an installed package, so it can't be compared to the distro default."
+                        ));
+                    }
+                }
+            } else if self.location.is_dir() {
+                // A directory that's linked as a whole
+                if execution_data.dry_run {
+                    info!("Dry run! Directory already exists; would ask whether to overwrite it.");
+                } else if !execution_data.input.is_interactive() {
+                    if execution_data.on_conflict != OnConflict::Theirs {
+                        return Err(Deferred(String::from(
+                            "Directory already exists; use `--on-conflict theirs` to overwrite it",
+                        ))
+                        .into());
+                    }
+                    info!("Directory already exists; overwriting.");
+                } else if !confirm("Directory already exists. Do you want to overwrite it?")? {
+                    return Err(eyre!("Aborted"));
+                }
             } else {
-                let diff = Command::new("diff")
-                    .arg(&target_file)
-                    .arg(&self.location)
-                    .output_fallible()?;
-
-                #[expect(clippy::collapsible_else_if)] // Clearer this way
-                if diff.status.success() {
+                let mine = self.read_location(execution_data)?;
+                let theirs = fs::read(&target_file).wrap_err("Failed to read file in repo")?;
+                if mine == theirs {
                     info!("File already exists but is identical; overwriting.");
+                } else if execution_data.dry_run {
+                    info!(
+                        "Dry run! File already exists and is different; would ask how to resolve the conflict. Diff between the repo content and actual content:\n{}",
+                        conflict::diff(&theirs, &mine)
+                    );
                 } else {
-                    if confirm(&format!(
-                        "File already exists and is different. Diff between the repo content and actual content:\n{}\nConsider adding an expected content string to the file to prevent this from happening in the future.\nDo you want to overwrite the file?",
-                        String::from_utf8_lossy(&diff.stdout)
-                    ))? {
-                        info!("Overwriting file according to user input.");
-                        debug!("Removing file");
-                        remove_file(&self.location).wrap_err("Failed to remove file.")?;
-                    } else {
-                        return Err(eyre!("Aborted"));
-                    }
+                    let base = Self::synced_version(self.relative_location(), execution_data)?;
+                    Self::resolve_conflict(
+                        &self.location,
+                        &mine,
+                        &theirs,
+                        base.as_deref(),
+                        &target_file,
+                        execution_data,
+                    )?;
                 }
             }
-        } else if let Some(expected_previous_content) = &self.expected_previous_content {
+            debug!("Removing file");
+            self.remove_location(execution_data)?;
+        } else if let Some(expected_previous_content) = &self.expected_previous_content
+            && !newly_added
+        {
+            // When newly added, the file was just moved into the repo
             return Err(eyre!(
-                "File was expected to exist and have content, but it doesn't exist. Expected content: '{expected_previous_content}'."
+                "File was expected to exist and have content, but it doesn't exist. Expected content: '{}'.",
+                String::from_utf8_lossy(expected_previous_content)
             ));
         }
 
-        create_parent(&self.location)?;
+        self.deploy(execution_data)
+    }
+
+    fn undo(&mut self, execution_data: &ExecutionData) -> Result<()> {
+        if self.per_file {
+            return self.undo_dir(execution_data);
+        }
+        if self.mode == LinkMode::Symlink && !self.location.is_symlink() {
+            return Err(eyre!("File is not a symlink."));
+        }
+        if self.mode != LinkMode::Symlink && !self.location.is_file() {
+            return Err(eyre!("File doesn't exist or is not a regular file."));
+        }
+        // A copy already is what materializing would result in, and it may have local edits
+        let keep = self.mode == LinkMode::Copy && self.undo_strategy == UndoStrategy::Materialize;
+        if !keep {
+            self.remove_location(execution_data)?;
+        }
+        let deployed = self.deployed_file(execution_data);
+        if !execution_data.dry_run && deployed.exists() {
+            remove_file(&deployed).wrap_err("Failed to remove deployed copy")?;
+        }
+
+        match self.undo_strategy {
+            UndoStrategy::Delete => {}
+            UndoStrategy::Materialize if keep => {}
+            UndoStrategy::Materialize => {
+                let mut cp = self.command("cp", execution_data);
+                cp.arg("--recursive")
+                    .arg("--no-target-directory")
+                    .arg(self.target_file(execution_data))
+                    .arg(&self.location);
+                Self::run_unless_dry_run(&mut cp, execution_data)?;
+                self.restore_metadata(execution_data)?;
+            }
+            UndoStrategy::Restore => {
+                let backup = self.backup_file(execution_data);
+                if backup.symlink_metadata().is_ok() {
+                    let mut mv = self.command("mv", execution_data);
+                    mv.arg("--no-target-directory")
+                        .arg(&backup)
+                        .arg(&self.location);
+                    Self::run_unless_dry_run(&mut mv, execution_data)?;
+                } else {
+                    warn!(
+                        "The original content of {} was not captured on this machine, so there's nothing to restore",
+                        self.location.display()
+                    );
+                }
+            }
+        }
 
-        Command::new("ln")
-            .arg(target_file)
-            .arg(&self.location)
-            .arg("--symbolic")
-            .status_checked()?;
         Ok(())
     }
 
-    fn undo(&mut self, _execution_data: &ExecutionData) -> Result<()> {
-        if !self.location.is_symlink() {
-            return Err(eyre!("File is not a symlink."));
+    fn is_satisfied(&self, execution_data: &ExecutionData) -> Result<bool> {
+        let target_file = self.target_file(execution_data);
+        if self.per_file {
+            if !target_file.is_dir() {
+                return Ok(false);
+            }
+            let ignore = self.ignore(&target_file, execution_data)?;
+            return Ok(tracked_files(&target_file, &ignore)?
+                .iter()
+                .all(|relative| {
+                    read_link(self.location.join(relative))
+                        .is_ok_and(|target| target == target_file.join(relative))
+                }));
         }
-        remove_file(&self.location).wrap_err("Failed to remove file as part of undo")
+        Ok(match self.mode {
+            LinkMode::Symlink => {
+                read_link(&self.location).is_ok_and(|target| target == target_file)
+            }
+            LinkMode::Hardlink => {
+                match (self.location.symlink_metadata(), target_file.metadata()) {
+                    (Ok(location), Ok(target)) => {
+                        location.dev() == target.dev() && location.ino() == target.ino()
+                    }
+                    _ => false,
+                }
+            }
+            LinkMode::Copy => {
+                !self.location.is_symlink() && self.location_is(&target_file, execution_data)
+            }
+        })
     }
 }
 
@@ -108,6 +336,563 @@ impl File {
         execution_data.file_dir.join(self.relative_location())
     }
 
+    /// Link (or copy) the file in the repo to the location
+    fn deploy(&self, execution_data: &ExecutionData) -> Result<()> {
+        let target_file = self.target_file(execution_data);
+        let mut command = match self.mode {
+            LinkMode::Symlink => {
+                let mut ln = self.command("ln", execution_data);
+                ln.arg(&target_file).arg(&self.location).arg("--symbolic");
+                ln
+            }
+            LinkMode::Hardlink => {
+                let mut ln = self.command("ln", execution_data);
+                ln.arg(&target_file).arg(&self.location);
+                ln
+            }
+            LinkMode::Copy => {
+                let mut cp = self.command("cp", execution_data);
+                cp.arg("--no-target-directory")
+                    .arg(&target_file)
+                    .arg(&self.location);
+                cp
+            }
+        };
+        if execution_data.dry_run {
+            log_dry_run(&command);
+            return Ok(());
+        }
+
+        self.create_location_parent(execution_data)?;
+        command.status_checked()?;
+        self.restore_metadata(execution_data)?;
+        if self.mode != LinkMode::Symlink {
+            // Remember what was deployed, to tell local edits apart from changes in the repo
+            let deployed = self.deployed_file(execution_data);
+            create_parent(&deployed)?;
+            fs::copy(&target_file, &deployed).wrap_err("Failed to remember deployed copy")?;
+        }
+        Ok(())
+    }
+
+    /// Redeploy a hardlink or copy when the file in the repo changed.
+    /// Leaves the file alone if it was edited locally.
+    pub fn redeploy(&self, execution_data: &ExecutionData) -> Result<()> {
+        if self.per_file {
+            // Files may have been added to the directory in the repo
+            return self.link_files(execution_data, false);
+        }
+        if self.mode == LinkMode::Symlink || self.is_satisfied(execution_data)? {
+            // Git doesn't keep the permissions when it updates the file in the repo
+            if self.location.exists() {
+                self.restore_metadata(execution_data)?;
+            }
+            return Ok(());
+        }
+        let target_file = self.target_file(execution_data);
+        let deployed = self.deployed_file(execution_data);
+        if self.location.exists() && !self.location_is(&deployed, execution_data) {
+            if same_content(&target_file, &deployed) {
+                info!(
+                    "{} was edited locally; use `falconf push` to commit the changes",
+                    self.location.display()
+                );
+                return Ok(());
+            }
+            if execution_data.dry_run {
+                info!(
+                    "Dry run! {} was edited both locally and in the repo; would ask how to resolve the conflict",
+                    self.location.display()
+                );
+                return Ok(());
+            }
+            let mine = self.read_location(execution_data)?;
+            let theirs = fs::read(&target_file).wrap_err("Failed to read file in repo")?;
+            let base = fs::read(&deployed).ok();
+            Self::resolve_conflict(
+                &self.location,
+                &mine,
+                &theirs,
+                base.as_deref(),
+                &target_file,
+                execution_data,
+            )?;
+        }
+
+        info!("Redeploying {}", self.location.display());
+        if self.location.exists() {
+            self.remove_location(execution_data)?;
+        }
+        self.deploy(execution_data)
+    }
+
+    /// Copy local edits of a hardlink or copy back into the repo.
+    /// Returns true if there were local edits.
+    pub fn collect_local_edits(&self, execution_data: &ExecutionData) -> Result<bool> {
+        if self.per_file {
+            return self.collect_new_files(execution_data);
+        }
+        if self.mode == LinkMode::Symlink
+            || !self.location.is_file()
+            || self.is_satisfied(execution_data)?
+        {
+            return Ok(false);
+        }
+        let target_file = self.target_file(execution_data);
+        let deployed = self.deployed_file(execution_data);
+        if self.location_is(&deployed, execution_data) {
+            // Not edited locally, but changed in the repo; sync will redeploy it
+            return Ok(false);
+        }
+        if deployed.exists() && !same_content(&target_file, &deployed) {
+            warn!(
+                "{} was edited both locally and in the repo; not collecting it. Copy the local edits into {} manually.",
+                self.location.display(),
+                target_file.display()
+            );
+            return Ok(false);
+        }
+
+        info!("Collecting local edits of {}", self.location.display());
+        fs::write(&target_file, self.read_location(execution_data)?)
+            .wrap_err("Failed to copy file into repo")?;
+        if self.mode == LinkMode::Hardlink {
+            // Link it again
+            self.remove_location(execution_data)?;
+            self.deploy(execution_data)?;
+        } else {
+            create_parent(&deployed)?;
+            fs::copy(&target_file, &deployed).wrap_err("Failed to remember deployed copy")?;
+        }
+        Ok(true)
+    }
+
+    fn execute_dir(&self, execution_data: &ExecutionData) -> Result<()> {
+        let target_dir = self.target_file(execution_data);
+        if self.location.is_symlink() {
+            return Err(eyre!("Directory already exists and is a symlink."));
+        }
+        if self.location.exists() {
+            self.backup(execution_data)?;
+        }
+
+        if !target_dir.exists() {
+            info!("Repo (target) directory doesn't exist, assuming this is newly added");
+            let ignore = self.ignore(&self.location, execution_data)?;
+            for relative in tracked_files(&self.location, &ignore)? {
+                let location = self.location.join(&relative);
+                let target = target_dir.join(&relative);
+                if execution_data.dry_run {
+                    info!(
+                        "Dry run! Would move the file into the repo: {} to {}",
+                        location.display(),
+                        target.display()
+                    );
+                } else {
+                    create_parent(&target)?;
+                    rename(&location, &target).wrap_err("Failed to move file into repo")?;
+                }
+            }
+            if execution_data.dry_run {
+                // Nothing was moved, so there's nothing to link yet
+                return Ok(());
+            }
+        }
+
+        self.link_files(execution_data, true)?;
+        self.restore_metadata(execution_data)
+    }
+
+    /// Link every file of the directory in the repo to its location.
+    /// Files that already exist and are different are overwritten after asking if `overwrite`,
+    /// and left alone otherwise.
+    fn link_files(&self, execution_data: &ExecutionData, overwrite: bool) -> Result<()> {
+        let target_dir = self.target_file(execution_data);
+        let ignore = self.ignore(&target_dir, execution_data)?;
+        for relative in tracked_files(&target_dir, &ignore)? {
+            let location = self.location.join(&relative);
+            let target = target_dir.join(&relative);
+            if read_link(&location).is_ok_and(|link| link == target) {
+                continue;
+            }
+
+            if location.symlink_metadata().is_ok() {
+                if !same_content(&location, &target) {
+                    if !overwrite {
+                        warn!(
+                            "{} already exists and is different from the file in the repo; not linking it",
+                            location.display()
+                        );
+                        continue;
+                    }
+                    let mine = fs::read(&location).wrap_err("Failed to read file")?;
+                    let theirs = fs::read(&target).wrap_err("Failed to read file in repo")?;
+                    if execution_data.dry_run {
+                        info!(
+                            "Dry run! {} already exists and is different; would ask how to resolve the conflict. Diff between the repo content and actual content:\n{}",
+                            location.display(),
+                            conflict::diff(&theirs, &mine)
+                        );
+                        continue;
+                    }
+                    let base = Self::synced_version(
+                        &self.relative_location().join(&relative),
+                        execution_data,
+                    )?;
+                    Self::resolve_conflict(
+                        &location,
+                        &mine,
+                        &theirs,
+                        base.as_deref(),
+                        &target,
+                        execution_data,
+                    )?;
+                }
+                if execution_data.dry_run {
+                    info!("Dry run! Would remove the file at {}", location.display());
+                } else {
+                    remove_file(&location).wrap_err("Failed to remove file")?;
+                }
+            }
+
+            let mut ln = Command::new("ln");
+            ln.arg("--symbolic").arg(&target).arg(&location);
+            if execution_data.dry_run {
+                log_dry_run(&ln);
+            } else {
+                create_parent(&location)?;
+                ln.status_checked()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn undo_dir(&self, execution_data: &ExecutionData) -> Result<()> {
+        let target_dir = self.target_file(execution_data);
+        let ignore = self.ignore(&target_dir, execution_data)?;
+        for relative in tracked_files(&target_dir, &ignore)? {
+            let location = self.location.join(&relative);
+            if read_link(&location).is_ok_and(|link| link == target_dir.join(&relative)) {
+                if execution_data.dry_run {
+                    info!("Dry run! Would remove the file at {}", location.display());
+                } else {
+                    remove_file(&location).wrap_err("Failed to remove file as part of undo")?;
+                }
+            }
+        }
+
+        // The directory itself stays, as it may contain ignored files
+        match self.undo_strategy {
+            UndoStrategy::Delete => {}
+            UndoStrategy::Materialize => {
+                let mut cp = Command::new("cp");
+                cp.arg("--recursive")
+                    .arg("--no-target-directory")
+                    .arg(&target_dir)
+                    .arg(&self.location);
+                Self::run_unless_dry_run(&mut cp, execution_data)?;
+            }
+            UndoStrategy::Restore => {
+                let backup = self.backup_file(execution_data);
+                if backup.is_dir() {
+                    let mut cp = Command::new("cp");
+                    cp.arg("--archive")
+                        .arg("--no-target-directory")
+                        .arg(&backup)
+                        .arg(&self.location);
+                    Self::run_unless_dry_run(&mut cp, execution_data)?;
+                    if !execution_data.dry_run {
+                        fs::remove_dir_all(&backup).wrap_err("Failed to remove backup")?;
+                    }
+                } else {
+                    warn!(
+                        "The original content of {} was not captured on this machine, so there's nothing to restore",
+                        self.location.display()
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move files that were created in the directory locally into the repo, and link them.
+    /// Returns true if there were new files.
+    fn collect_new_files(&self, execution_data: &ExecutionData) -> Result<bool> {
+        if !self.location.is_dir() {
+            return Ok(false);
+        }
+        let target_dir = self.target_file(execution_data);
+        let ignore = self.ignore(&self.location, execution_data)?;
+        let mut collected = false;
+        for relative in tracked_files(&self.location, &ignore)? {
+            let location = self.location.join(&relative);
+            if location.is_symlink() {
+                // Already linked
+                continue;
+            }
+            let target = target_dir.join(&relative);
+            if target.exists() {
+                warn!(
+                    "{} exists both locally and in the repo; not collecting it. Remove one of them.",
+                    location.display()
+                );
+                continue;
+            }
+
+            info!("Collecting new file {}", location.display());
+            create_parent(&target)?;
+            rename(&location, &target).wrap_err("Failed to move file into repo")?;
+            let mut ln = Command::new("ln");
+            ln.arg("--symbolic").arg(&target).arg(&location);
+            ln.status_checked()?;
+            collected = true;
+        }
+        Ok(collected)
+    }
+
+    /// Ask the user how to resolve a conflict between the local version of a file and the version
+    /// in the repo, and put the local version or a merge in the repo if that's what they chose
+    fn resolve_conflict(
+        location: &Path,
+        mine: &[u8],
+        theirs: &[u8],
+        base: Option<&[u8]>,
+        target: &Path,
+        execution_data: &ExecutionData,
+    ) -> Result<()> {
+        let resolved = if execution_data.input.is_interactive() {
+            conflict::resolve(location, mine, theirs, base, &execution_data.diff_viewer)?
+        } else {
+            match execution_data.on_conflict {
+                OnConflict::Defer => {
+                    return Err(Deferred(format!(
+                        "{} is different from the version in the repo",
+                        location.display()
+                    ))
+                    .into());
+                }
+                OnConflict::Mine => Some(mine.to_vec()),
+                OnConflict::Theirs => {
+                    info!(
+                        "Overwriting {} with the version in the repo",
+                        location.display()
+                    );
+                    None
+                }
+            }
+        };
+        if let Some(content) = resolved {
+            fs::write(target, content).wrap_err("Failed to write file in repo")?;
+            info!(
+                "Put the resolved version of {} in the repo; use `falconf push` to commit it",
+                location.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// The ignore patterns for a directory: those in the global `.falconfignore` in the root of
+    /// the repo, and those in the `.falconfignore` in the directory itself
+    fn ignore(&self, dir: &Path, execution_data: &ExecutionData) -> Result<Gitignore> {
+        let mut builder = GitignoreBuilder::new(dir);
+        for file in [execution_data.ignore_file.clone(), dir.join(IGNORE_FILE)] {
+            if file.exists()
+                && let Some(err) = builder.add(&file)
+            {
+                return Err(err).wrap_err_with(|| format!("Failed to read {}", file.display()));
+            }
+        }
+        builder.build().wrap_err("Failed to parse ignore patterns")
+    }
+
+    /// The version of a file in the repo that was last synced, relative to the file dir
+    fn synced_version(file: &Path, execution_data: &ExecutionData) -> Result<Option<Vec<u8>>> {
+        execution_data.synced_commit.map_or(Ok(None), |commit| {
+            conflict::synced_version(&execution_data.file_dir, commit, file)
+        })
+    }
+
+    /// Return the location of the copy of what was last deployed, for hardlinks and copies
+    fn deployed_file(&self, execution_data: &ExecutionData) -> PathBuf {
+        execution_data.deployed_dir.join(self.relative_location())
+    }
+
+    /// Return the location of the original content of the file, from before it was linked
+    fn backup_file(&self, execution_data: &ExecutionData) -> PathBuf {
+        execution_data.backup_dir.join(self.relative_location())
+    }
+
+    /// Keep the original content of the file, so it can be restored when undoing.
+    /// Only the content from before the file was first linked is kept.
+    fn backup(&self, execution_data: &ExecutionData) -> Result<()> {
+        let backup = self.backup_file(execution_data);
+        if backup.symlink_metadata().is_ok() {
+            debug!("Keeping existing backup at {}", backup.display());
+            return Ok(());
+        }
+        let mut cp = self.command("cp", execution_data);
+        cp.arg("--archive")
+            .arg("--no-target-directory")
+            .arg(&self.location)
+            .arg(&backup);
+        if !execution_data.dry_run {
+            create_parent(&backup)?;
+        }
+        Self::run_unless_dry_run(&mut cp, execution_data)
+    }
+
+    /// Create a command, that runs with root privileges if this is a sudo file
+    fn command(&self, program: &str, execution_data: &ExecutionData) -> Command {
+        if_sudo(program, self.sudo, &execution_data.escalate)
+    }
+
+    fn remove_location(&self, execution_data: &ExecutionData) -> Result<()> {
+        if self.sudo {
+            let mut rm = self.command("rm", execution_data);
+            rm.arg("--").arg(&self.location);
+            Self::run_unless_dry_run(&mut rm, execution_data)
+        } else if execution_data.dry_run {
+            info!(
+                "Dry run! Would remove the file at {}",
+                self.location.display()
+            );
+            Ok(())
+        } else if self.location.is_dir() && !self.location.is_symlink() {
+            fs::remove_dir_all(&self.location).wrap_err("Failed to remove directory")
+        } else {
+            remove_file(&self.location).wrap_err("Failed to remove file")
+        }
+    }
+
+    fn create_location_parent(&self, execution_data: &ExecutionData) -> Result<()> {
+        if self.sudo {
+            if let Some(parent) = self.location.parent() {
+                let mut mkdir = self.command("mkdir", execution_data);
+                mkdir.arg("--parents").arg(parent);
+                Self::run_unless_dry_run(&mut mkdir, execution_data)?;
+            }
+            Ok(())
+        } else {
+            create_parent(&self.location)
+        }
+    }
+
+    /// Read the file at the location, with root privileges if needed
+    fn read_location(&self, execution_data: &ExecutionData) -> Result<Vec<u8>> {
+        match fs::read(&self.location) {
+            Err(err) if self.sudo && err.kind() == io::ErrorKind::PermissionDenied => {
+                let output = self
+                    .command("cat", execution_data)
+                    .arg("--")
+                    .arg(&self.location)
+                    .output_fallible()?;
+                if !output.status.success() {
+                    return Err(eyre!(
+                        "Failed to read {}: {}",
+                        self.location.display(),
+                        String::from_utf8_lossy(&output.stderr)
+                    ));
+                }
+                Ok(output.stdout)
+            }
+            result => result.wrap_err("Failed to read file"),
+        }
+    }
+
+    /// Returns true if the file at the location has the same content as `other`
+    fn location_is(&self, other: &Path, execution_data: &ExecutionData) -> bool {
+        match (self.read_location(execution_data), fs::read(other)) {
+            (Ok(location), Ok(other)) => location == other,
+            _ => false,
+        }
+    }
+
+    /// Copy a root-owned file into the repo, and give the copy to the owner of the repo
+    fn copy_into_repo(&self, target_file: &Path, execution_data: &ExecutionData) -> Result<()> {
+        let mut cp = self.command("cp", execution_data);
+        cp.arg("--no-target-directory")
+            .arg(&self.location)
+            .arg(target_file);
+        cp.status_checked()?;
+        let owner = execution_data.file_dir.metadata()?;
+        let mut chown = self.command("chown", execution_data);
+        chown
+            .arg(format!("{}:{}", owner.uid(), owner.gid()))
+            .arg(target_file);
+        chown.status_checked()?;
+        Ok(())
+    }
+
+    /// Set the recorded permissions and ownership on the file at the location, if they differ
+    fn restore_metadata(&self, execution_data: &ExecutionData) -> Result<()> {
+        let Some(metadata) = &self.metadata else {
+            return Ok(());
+        };
+        let current = FileMetadata::of(&self.location, self.sudo).ok();
+        if current.map(|current| current.mode) != Some(metadata.mode) {
+            // Follows symlinks, so for symlinks this sets the mode of the file in the repo
+            let mut chmod = self.command("chmod", execution_data);
+            chmod
+                .arg(format!("{:o}", metadata.mode))
+                .arg(&self.location);
+            Self::run_unless_dry_run(&mut chmod, execution_data)?;
+        }
+        // The file in the repo should stay owned by the user
+        if let Some(owner) = metadata.owner
+            && self.mode == LinkMode::Copy
+            && current.and_then(|current| current.owner) != Some(owner)
+        {
+            let mut chown = self.command("chown", execution_data);
+            chown
+                .arg(format!("{}:{}", owner.uid, owner.gid))
+                .arg(&self.location);
+            Self::run_unless_dry_run(&mut chown, execution_data)?;
+        }
+        Ok(())
+    }
+
+    /// Record permission (and ownership) changes of the file at the location.
+    /// Returns a description of the change, if there was one.
+    pub fn collect_metadata(&mut self) -> Option<String> {
+        let recorded = self.metadata?;
+        let current = FileMetadata::of(&self.location, self.sudo).ok()?;
+        if current == recorded {
+            return None;
+        }
+        info!(
+            "Recording permission changes of {}",
+            self.location.display()
+        );
+        self.metadata = Some(current);
+        let mut description = format!("{}:", self.location.display());
+        if current.mode != recorded.mode {
+            description += &format!(" mode {:o} -> {:o}", recorded.mode, current.mode);
+        }
+        if let (Some(recorded), Some(current)) = (recorded.owner, current.owner)
+            && current != recorded
+        {
+            description += &format!(
+                " owner {}:{} -> {}:{}",
+                recorded.uid, recorded.gid, current.uid, current.gid
+            );
+        }
+        Some(description)
+    }
+
+    fn run_unless_dry_run(command: &mut Command, execution_data: &ExecutionData) -> Result<()> {
+        if execution_data.dry_run {
+            log_dry_run(command);
+        } else {
+            command.status_checked()?;
+        }
+        Ok(())
+    }
+
+    pub const fn set_undo_strategy(&mut self, undo_strategy: UndoStrategy) {
+        self.undo_strategy = undo_strategy;
+    }
+
     /// Return the file's location relative to /; the target of the symlink relative to the file dir
     pub fn relative_location(&self) -> &Path {
         #[expect(clippy::missing_panics_doc, reason = "illegal configuration")]
@@ -124,10 +909,26 @@ impl File {
         }
 
         let location = args.value[0].clone();
-        // TODO(low): This does resolve symlinks, is that okay?
-        let location = std::fs::canonicalize(&location).wrap_err_with(|| {
-            format!("Failed to canonicalize file '{location}'. Does it exist?")
-        })?;
+        let location = if args.adopt && Path::new(&location).is_symlink() {
+            // The symlink itself is replaced, so only resolve its parent
+            let location = std::path::absolute(&location)?;
+            let (Some(parent), Some(name)) = (location.parent(), location.file_name()) else {
+                return Err(eyre!("Invalid file location '{location:?}'"));
+            };
+            std::fs::canonicalize(parent)
+                .wrap_err("Failed to canonicalize parent directory")?
+                .join(name)
+        } else {
+            // TODO(low): This does resolve symlinks, is that okay?
+            std::fs::canonicalize(&location).wrap_err_with(|| {
+                format!("Failed to canonicalize file '{location}'. Does it exist?")
+            })?
+        };
+        if location.is_symlink() && !location.is_file() {
+            return Err(eyre!(
+                "`--adopt` only works with symlinks to regular files."
+            ));
+        }
 
         if !location.starts_with(PathBuf::from("/")) {
             return Err(eyre!(
@@ -135,31 +936,360 @@ impl File {
             ));
         }
 
-        Ok(Self {
+        // Symlinks into the home directory are a bad idea for root-owned files
+        let mode = args.mode.unwrap_or(if args.sudo {
+            LinkMode::Copy
+        } else {
+            LinkMode::Symlink
+        });
+        if mode != LinkMode::Symlink && !location.is_file() {
+            return Err(eyre!(
+                "The {mode:?} mode only works with regular files, and '{location:?}' is not one."
+            ));
+        }
+        let per_file = location.is_dir();
+        if per_file && args.sudo {
+            return Err(eyre!("`--sudo` doesn't work with directories."));
+        }
+
+        let metadata = Some(FileMetadata::of(&location, args.sudo)?);
+
+        let mut file = Self {
             location,
+            mode,
+            per_file,
+            sudo: args.sudo,
+            metadata,
             expected_previous_content: None,
-        })
+            expect_distro_default: false,
+            adopt: args.adopt,
+            moved_from: vec![],
+            undo_strategy: UndoStrategy::default(),
+        };
+        if let Some(source) = &args.expect_content {
+            file.expect_content(read_file_or_stdin(source)?);
+        }
+        if args.expect_distro_default {
+            file.expect_distro_default()?;
+        }
+        Ok(file)
+    }
+
+    /// If the location is a symlink into another git repository (like a dotfiles repo), return
+    /// the file it points to and that repository
+    pub fn adopted_repository(&self) -> Option<(PathBuf, PathBuf)> {
+        if !self.location.is_symlink() {
+            return None;
+        }
+        let file = fs::canonicalize(&self.location).ok()?;
+        let repository = file
+            .ancestors()
+            .skip(1)
+            .find(|dir| dir.join(".git").exists())?
+            .to_path_buf();
+        Some((file, repository))
+    }
+
+    pub fn move_to(&mut self, location: PathBuf) -> Result<()> {
+        if !location.starts_with("/") {
+            return Err(eyre!(
+                "File location must be an absolute path (starting with '/'), got '{location:?}'."
+            ));
+        }
+        // Moving back makes a previous location current again
+        self.moved_from.retain(|moved_from| *moved_from != location);
+        let moved_from = std::mem::replace(&mut self.location, location);
+        self.moved_from.push(moved_from);
+        Ok(())
+    }
+
+    /// Remove what was deployed at the locations the file was moved from
+    fn clean_up_moved_from(&self, execution_data: &ExecutionData) -> Result<()> {
+        for moved_from in &self.moved_from {
+            self.clean_up_location(moved_from, execution_data)?;
+        }
+        Ok(())
+    }
+
+    /// Remove what was deployed at a location the file was moved from, unless it was edited
+    /// locally, and keep its backup
+    fn clean_up_location(&self, moved_from: &Path, execution_data: &ExecutionData) -> Result<()> {
+        let old = Self {
+            location: moved_from.to_path_buf(),
+            moved_from: vec![],
+            ..self.clone()
+        };
+        let old_target = old.target_file(execution_data);
+
+        if self.per_file {
+            if moved_from.is_dir() {
+                for link in links_into(moved_from, &old_target)? {
+                    if execution_data.dry_run {
+                        info!("Dry run! Would remove the file at {}", link.display());
+                    } else {
+                        remove_file(&link).wrap_err("Failed to remove file")?;
+                    }
+                }
+            }
+        } else if moved_from.symlink_metadata().is_ok() {
+            let deployed_here = match self.mode {
+                LinkMode::Symlink => read_link(moved_from).is_ok_and(|link| link == old_target),
+                LinkMode::Hardlink | LinkMode::Copy => {
+                    old.location_is(&old.deployed_file(execution_data), execution_data)
+                }
+            };
+            if deployed_here {
+                info!("Removing {}, as the file was moved", moved_from.display());
+                old.remove_location(execution_data)?;
+            } else {
+                warn!(
+                    "{} was moved, but it was changed locally, so it's left alone",
+                    moved_from.display()
+                );
+            }
+        }
+
+        if !execution_data.dry_run {
+            let old_deployed = old.deployed_file(execution_data);
+            if old_deployed.exists() {
+                remove_file(&old_deployed).wrap_err("Failed to remove deployed copy")?;
+            }
+            let old_backup = old.backup_file(execution_data);
+            let backup = self.backup_file(execution_data);
+            if old_backup.symlink_metadata().is_ok() && backup.symlink_metadata().is_err() {
+                create_parent(&backup)?;
+                rename(&old_backup, &backup).wrap_err("Failed to move backup")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Only overwrite the file on machines where it already exists if it has this content
+    pub fn expect_content(&mut self, content: Vec<u8>) {
+        self.expected_previous_content = Some(content);
+        self.expect_distro_default = false;
+    }
+
+    /// Only overwrite the file on machines where it already exists if it's the distro default
+    pub fn expect_distro_default(&mut self) -> Result<()> {
+        if distro_default_md5(&self.location)?.is_none() {
+            return Err(eyre!(
+                "{} is not a conffile of an installed package, so it has no distro default.",
+                self.location.display()
+            ));
+        }
+        self.expected_previous_content = None;
+        self.expect_distro_default = true;
+        Ok(())
+    }
+
+    /// Returns false if there was nothing to remove
+    pub fn remove_expected_content(&mut self) -> bool {
+        let had_expectation =
+            self.expected_previous_content.is_some() || self.expect_distro_default;
+        self.expected_previous_content = None;
+        self.expect_distro_default = false;
+        had_expectation
     }
 }
 
 impl Display for File {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Tracking file at: {}", self.location.display())
+        write!(f, "Tracking file at: {}", self.location.display())?;
+        if self.sudo {
+            write!(f, " (sudo)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Deserialize the previous locations of a file, also from when only the last one was kept
+fn deserialize_moved_from<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<PathBuf>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum MovedFrom {
+        All(Vec<PathBuf>),
+        Last(Option<PathBuf>),
     }
+    Ok(match MovedFrom::deserialize(deserializer)? {
+        MovedFrom::All(all) => all,
+        MovedFrom::Last(last) => last.into_iter().collect(),
+    })
+}
+
+/// (De)serialize file content as a string if it's text, so it's readable in the data file,
+/// and as bytes otherwise
+mod text_or_bytes {
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::fmt::Formatter;
+
+    struct Content<'a>(&'a [u8]);
+
+    impl Serialize for Content<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match str::from_utf8(self.0) {
+                Ok(text) => serializer.serialize_str(text),
+                Err(_) => serializer.serialize_bytes(self.0),
+            }
+        }
+    }
+
+    struct ContentBuf(Vec<u8>);
+
+    impl<'de> Deserialize<'de> for ContentBuf {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(ContentVisitor)
+        }
+    }
+
+    struct ContentVisitor;
+
+    impl<'de> Visitor<'de> for ContentVisitor {
+        type Value = ContentBuf;
+
+        fn expecting(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "a string or bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            Ok(ContentBuf(v.as_bytes().to_vec()))
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            Ok(ContentBuf(v.to_vec()))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut content = vec![];
+            while let Some(byte) = seq.next_element()? {
+                content.push(byte);
+            }
+            Ok(ContentBuf(content))
+        }
+    }
+
+    #[expect(clippy::ref_option, reason = "required by serde's `with`")]
+    pub fn serialize<S: Serializer>(
+        content: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        content.as_deref().map(Content).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Ok(Option::<ContentBuf>::deserialize(deserializer)?.map(|content| content.0))
+    }
+}
+
+/// The md5 hash of the content a file had when its package installed it, if it's a conffile
+fn distro_default_md5(location: &Path) -> Result<Option<String>> {
+    let owner = Command::new("dpkg")
+        .arg("--search")
+        .arg(location)
+        .output_fallible()?;
+    if !owner.status.success() {
+        return Ok(None);
+    }
+    // Like `package1, package2: /etc/file`
+    let owner = String::from_utf8_lossy(&owner.stdout);
+    let Some((packages, _)) = owner
+        .lines()
+        .filter(|line| !line.starts_with("diversion "))
+        .find_map(|line| line.split_once(": "))
+    else {
+        return Ok(None);
+    };
+    for package in packages.split(", ") {
+        let conffiles = Command::new("dpkg-query")
+            .arg("--show")
+            .arg("--showformat=${Conffiles}")
+            .arg(package)
+            .output_fallible()?;
+        if let Some(md5) = parse_conffiles(&String::from_utf8_lossy(&conffiles.stdout), location) {
+            return Ok(Some(md5));
+        }
+    }
+    Ok(None)
+}
+
+/// Find the md5 hash of a file in the conffiles of a package, with lines like ` /etc/file <md5>`
+fn parse_conffiles(conffiles: &str, location: &Path) -> Option<String> {
+    conffiles.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        if Path::new(parts.next()?) == location {
+            parts.next().map(str::to_owned)
+        } else {
+            None
+        }
+    })
+}
+
+/// List the symlinks in a directory that point into `target_dir`
+fn links_into(dir: &Path, target_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut links = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        for entry in fs::read_dir(&current)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_symlink()
+                && read_link(entry.path()).is_ok_and(|link| link.starts_with(target_dir))
+            {
+                links.push(entry.path());
+            }
+        }
+    }
+    Ok(links)
+}
+
+/// List the files in a directory that aren't ignored, relative to the directory
+fn tracked_files(dir: &Path, ignore: &Gitignore) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        let entries = fs::read_dir(&current)
+            .wrap_err_with(|| format!("Failed to read directory {}", current.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if ignore.matched(&path, file_type.is_dir()).is_ignore() {
+                debug!("Ignoring {}", path.display());
+            } else if file_type.is_dir() {
+                dirs.push(path);
+            } else if file_type.is_file() || file_type.is_symlink() {
+                files.push(path.strip_prefix(dir)?.to_path_buf());
+            } else {
+                debug!("Skipping {}, which is not a regular file", path.display());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
 }
 
 // File is mostly tested in sync
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::cli::add::tests::add_util_no_test_run;
+    use crate::cli::add;
+    use crate::cli::add::tests::{add_args_util, add_util_no_test_run};
     use crate::cli::init::tests::init_util;
     use crate::cli::sync::sync;
-    use crate::cli::{TopLevelArgs, sync};
+    use crate::cli::{PieceRef, TopLevelArgs, sync, undo};
+    use crate::full_piece::FullPiece;
+    use crate::installation::Installation;
     use crate::testing::TestRemote;
     use color_eyre::eyre::OptionExt;
     use std::fs;
-    use std::fs::{create_dir, remove_dir_all};
+    use std::fs::{create_dir, create_dir_all, remove_dir_all};
     use std::io::Write;
     use tempfile::TempDir;
 
@@ -192,7 +1322,7 @@ mod tests {
         let local_2 = init_util(&remote, false)?;
 
         let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), false);
-        let args = sync::Args {};
+        let args = sync::Args::default();
         sync(top_level_args, args)?;
 
         // After syncing, the dir is created
@@ -203,4 +1333,350 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_undo_restore() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let test_1 = temp.path().join("test_1");
+        fs::File::create(&test_1)?.write_all(b"original")?;
+
+        let local_1 = init_util(&remote, true)?;
+        let test_1_s = test_1.to_str().ok_or_eyre("Invalid path")?.to_string();
+        add_util_no_test_run(local_1.path(), crate::cli::add::Piece::File, vec![test_1_s])?;
+        assert!(test_1.is_symlink());
+        fs::write(&test_1, "changed")?;
+
+        let top_level_args = TopLevelArgs::new_testing(local_1.path().clone(), false);
+        let args = undo::Args {
+            pieces: vec![PieceRef::Last],
+            done_here: false,
+            materialize: false,
+            restore: true,
+            delete: false,
+        };
+        undo::undo(top_level_args, args)?;
+
+        // The content from before it was added is back
+        assert!(!test_1.is_symlink());
+        assert_eq!(fs::read_to_string(&test_1)?, "original");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_mode() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let test_1 = temp.path().join("test_1");
+        fs::write(&test_1, "v1")?;
+
+        let local_1 = init_util(&remote, true)?;
+        let mut args = add_args_util(
+            Some(add::Piece::File),
+            vec![test_1.to_str().ok_or_eyre("Invalid path")?.to_string()],
+            None,
+        );
+        args.mode = Some(LinkMode::Copy);
+        add::add(
+            TopLevelArgs::new_testing(local_1.path().clone(), false),
+            args,
+        )?;
+        assert!(!test_1.is_symlink());
+        assert_eq!(fs::read_to_string(&test_1)?, "v1");
+
+        let top_level_args = TopLevelArgs::new_testing(local_1.path().clone(), false);
+        let mut installation = Installation::get(&top_level_args)?;
+        let machine = *installation.machine();
+        let execution_data = ExecutionData::new(&installation, &top_level_args)?;
+        let target_file = execution_data.file_dir.join(test_1.strip_prefix("/")?);
+
+        // Changed in the repo, so it's redeployed
+        fs::write(&target_file, "v2")?;
+        sync(top_level_args.clone(), sync::Args::default())?;
+        assert_eq!(fs::read_to_string(&test_1)?, "v2");
+
+        // Changed locally, so it's collected
+        fs::write(&test_1, "v3")?;
+        FullPiece::collect_local_edits(
+            installation.repo_mut().data_mut().pieces_mut(),
+            &machine,
+            &execution_data,
+        )?;
+        assert_eq!(fs::read_to_string(&target_file)?, "v3");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sudo() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let test_1 = temp.path().join("test_1");
+        fs::write(&test_1, "content")?;
+        fs::set_permissions(&test_1, fs::Permissions::from_mode(0o640))?;
+
+        // Tests run as root, so no actual escalation is needed
+        let top_level_args = |path: &PathBuf| {
+            let mut top_level_args = TopLevelArgs::new_testing(path.clone(), false);
+            top_level_args.config.escalate = String::from("env");
+            top_level_args
+        };
+
+        let local_1 = init_util(&remote, true)?;
+        let mut args = add_args_util(
+            Some(add::Piece::File),
+            vec![test_1.to_str().ok_or_eyre("Invalid path")?.to_string()],
+            None,
+        );
+        args.sudo = true;
+        add::add(top_level_args(local_1.path()), args)?;
+        // Copy by default
+        assert!(!test_1.is_symlink());
+        assert_eq!(fs::read_to_string(&test_1)?, "content");
+        assert_eq!(fs::metadata(&test_1)?.mode() & 0o777, 0o640);
+
+        // The permissions are restored on other machines
+        fs::remove_file(&test_1)?;
+        let local_2 = init_util(&remote, false)?;
+        sync(top_level_args(local_2.path()), sync::Args::default())?;
+        assert_eq!(fs::read_to_string(&test_1)?, "content");
+        assert_eq!(fs::metadata(&test_1)?.mode() & 0o777, 0o640);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_permissions() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let test_1 = temp.path().join("test_1");
+        fs::write(&test_1, "secret")?;
+        fs::set_permissions(&test_1, fs::Permissions::from_mode(0o600))?;
+
+        let local_1 = init_util(&remote, true)?;
+        let args = add_args_util(
+            Some(add::Piece::File),
+            vec![test_1.to_str().ok_or_eyre("Invalid path")?.to_string()],
+            None,
+        );
+        add::add(
+            TopLevelArgs::new_testing(local_1.path().clone(), false),
+            args,
+        )?;
+
+        // Git only keeps the executable bit, but the mode is restored
+        fs::remove_file(&test_1)?;
+        let local_2 = init_util(&remote, false)?;
+        let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), false);
+        sync(top_level_args.clone(), sync::Args::default())?;
+        assert_eq!(fs::metadata(&test_1)?.mode() & 0o777, 0o600);
+
+        // Permission changes are recorded
+        fs::set_permissions(&test_1, fs::Permissions::from_mode(0o640))?;
+        let mut installation = Installation::get(&top_level_args)?;
+        let machine = *installation.machine();
+        let execution_data = ExecutionData::new(&installation, &top_level_args)?;
+        let changes = FullPiece::collect_local_edits(
+            installation.repo_mut().data_mut().pieces_mut(),
+            &machine,
+            &execution_data,
+        )?;
+        assert_eq!(
+            changes,
+            vec![format!("{}: mode 600 -> 640", test_1.display())]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignore() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let test_d = temp.path().join("dir");
+        create_dir_all(test_d.join("cache"))?;
+        fs::write(test_d.join(IGNORE_FILE), "*.log\ncache/\n")?;
+        fs::write(test_d.join("config"), "config_content")?;
+        fs::write(test_d.join("debug.log"), "log_content")?;
+        fs::write(test_d.join("cache").join("data"), "cache_content")?;
+
+        let local_1 = init_util(&remote, true)?;
+        let test_d_s = test_d.to_str().ok_or_eyre("Invalid path")?.to_string();
+        add_util_no_test_run(local_1.path(), crate::cli::add::Piece::File, vec![test_d_s])?;
+
+        let top_level_args = TopLevelArgs::new_testing(local_1.path().clone(), false);
+        let mut installation = Installation::get(&top_level_args)?;
+        let machine = *installation.machine();
+        let execution_data = ExecutionData::new(&installation, &top_level_args)?;
+        let target_d = execution_data.file_dir.join(test_d.strip_prefix("/")?);
+
+        // Only the files that aren't ignored are linked, the rest is left alone
+        assert!(!test_d.is_symlink());
+        assert!(test_d.join("config").is_symlink());
+        assert!(test_d.join(IGNORE_FILE).is_symlink());
+        assert!(!test_d.join("debug.log").is_symlink());
+        assert_eq!(fs::read_to_string(test_d.join("debug.log"))?, "log_content");
+        assert!(!target_d.join("debug.log").exists());
+        assert!(!target_d.join("cache").exists());
+
+        // New files are collected on push, unless they're ignored
+        fs::write(test_d.join("new"), "new_content")?;
+        fs::write(test_d.join("new.log"), "log_content")?;
+        FullPiece::collect_local_edits(
+            installation.repo_mut().data_mut().pieces_mut(),
+            &machine,
+            &execution_data,
+        )?;
+        assert!(test_d.join("new").is_symlink());
+        assert_eq!(fs::read_to_string(target_d.join("new"))?, "new_content");
+        assert!(!test_d.join("new.log").is_symlink());
+        assert!(!target_d.join("new.log").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_text_or_bytes() -> Result<()> {
+        #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+        struct Expected {
+            #[serde(with = "text_or_bytes")]
+            content: Option<Vec<u8>>,
+        }
+
+        // Text is stored as a string, like before binary content was supported
+        let text = Expected {
+            content: Some(b"text".to_vec()),
+        };
+        assert_eq!(ron::to_string(&text)?, r#"(content:Some("text"))"#);
+        assert_eq!(
+            ron::from_str::<Expected>(r#"(content:Some("text"))"#)?,
+            text
+        );
+
+        for content in [Some(vec![0, 159, 146, 150]), None] {
+            let expected = Expected { content };
+            assert_eq!(
+                ron::from_str::<Expected>(&ron::to_string(&expected)?)?,
+                expected
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_moved_from() -> Result<()> {
+        #[derive(Deserialize, Debug)]
+        struct Moved {
+            #[serde(default, deserialize_with = "deserialize_moved_from")]
+            moved_from: Vec<PathBuf>,
+        }
+
+        for (data, expected) in [
+            (r#"(moved_from:["/a","/b"])"#, vec!["/a", "/b"]),
+            (r#"(moved_from:Some("/a"))"#, vec!["/a"]),
+            ("(moved_from:None)", vec![]),
+            ("()", vec![]),
+        ] {
+            let expected: Vec<PathBuf> = expected.into_iter().map(PathBuf::from).collect();
+            assert_eq!(ron::from_str::<Moved>(data)?.moved_from, expected, "{data}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expect_content() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let test_1 = temp.path().join("test_1");
+        let stock = temp.path().join("stock");
+        // Binary content can be expected too
+        let stock_content = [0, 159, 146, 150];
+        fs::write(&test_1, "custom")?;
+        fs::write(&stock, stock_content)?;
+
+        let local_1 = init_util(&remote, true)?;
+        let mut args = add_args_util(
+            Some(add::Piece::File),
+            vec![test_1.to_str().ok_or_eyre("Invalid path")?.to_string()],
+            None,
+        );
+        args.expect_content = Some(stock.to_str().ok_or_eyre("Invalid path")?.to_string());
+        add::add(
+            TopLevelArgs::new_testing(local_1.path().clone(), false),
+            args,
+        )?;
+
+        // A customized file is not overwritten
+        fs::remove_file(&test_1)?;
+        fs::write(&test_1, "customized elsewhere")?;
+        let local_2 = init_util(&remote, false)?;
+        let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), false);
+        assert!(sync(top_level_args.clone(), sync::Args::default()).is_err());
+        assert!(!test_1.is_symlink());
+
+        // A stock file is overwritten without asking
+        fs::write(&test_1, stock_content)?;
+        sync(top_level_args, sync::Args::default())?;
+        assert!(test_1.is_symlink());
+        assert_eq!(fs::read_to_string(&test_1)?, "custom");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_conffiles() {
+        let conffiles = " /etc/debian_version 8031d1483ffa9c819e6be94c6c77fd2a\n /etc/issue 349d61a0e072d678e3e94923f0c3ce0e obsolete\n";
+        assert_eq!(
+            parse_conffiles(conffiles, Path::new("/etc/issue")),
+            Some(String::from("349d61a0e072d678e3e94923f0c3ce0e"))
+        );
+        assert_eq!(parse_conffiles(conffiles, Path::new("/etc/motd")), None);
+    }
+
+    #[test]
+    fn test_adopt() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = TempDir::new()?;
+        let dotfiles = temp.path().join("dotfiles");
+        create_dir_all(dotfiles.join(".git"))?;
+        let original = dotfiles.join("config");
+        fs::write(&original, "content")?;
+        let test_1 = temp.path().join("config");
+        std::os::unix::fs::symlink(&original, &test_1)?;
+
+        let local_1 = init_util(&remote, true)?;
+        let mut args = add_args_util(
+            Some(add::Piece::File),
+            vec![test_1.to_str().ok_or_eyre("Invalid path")?.to_string()],
+            None,
+        );
+        args.adopt = true;
+        let file = File::from_cli(&args)?;
+        assert_eq!(file.location, test_1);
+        assert_eq!(
+            file.adopted_repository(),
+            Some((original.clone(), dotfiles.clone()))
+        );
+
+        // Without a repository, so we aren't asked to note the migration
+        remove_dir_all(dotfiles.join(".git"))?;
+        add::add(
+            TopLevelArgs::new_testing(local_1.path().clone(), false),
+            args,
+        )?;
+        assert!(test_1.is_symlink());
+        assert_ne!(fs::read_link(&test_1)?, original);
+        assert_eq!(fs::read_to_string(&test_1)?, "content");
+        // The original file is left alone
+        assert_eq!(fs::read_to_string(&original)?, "content");
+
+        Ok(())
+    }
 }
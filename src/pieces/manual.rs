@@ -1,30 +1,47 @@
 use crate::cli::add;
 use crate::execution_data::ExecutionData;
 use crate::piece::NonBulkPiece;
+use crate::report::Deferred;
 use crate::utils::press_enter;
 use color_eyre::Result;
+use log::info;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Manual {
     /// The message to show the user
     message: String,
 }
 
 impl NonBulkPiece for Manual {
-    fn execute(&mut self, _execution_data: &ExecutionData) -> Result<()> {
-        Self::print_message(&self.message)
+    fn execute(&mut self, execution_data: &ExecutionData) -> Result<()> {
+        Self::print_message(&self.message, execution_data)
     }
 
-    fn undo(&mut self, _execution_data: &ExecutionData) -> Result<()> {
-        Self::print_message(&format!("UNDO the following change: {}", self.message))
+    fn undo(&mut self, execution_data: &ExecutionData) -> Result<()> {
+        Self::print_message(
+            &format!("UNDO the following change: {}", self.message),
+            execution_data,
+        )
+    }
+
+    fn is_satisfied(&self, _execution_data: &ExecutionData) -> Result<bool> {
+        // There's no way to know
+        Ok(false)
     }
 }
 
 impl Manual {
     #[expect(clippy::print_stdout)]
-    fn print_message(message: &str) -> Result<()> {
+    fn print_message(message: &str, execution_data: &ExecutionData) -> Result<()> {
+        if execution_data.dry_run {
+            info!("Dry run! Would ask for a manual action: {message}");
+            return Ok(());
+        }
+        if !execution_data.input.is_interactive() {
+            return Err(Deferred(format!("Manual action required: {message}")).into());
+        }
         println!("Manual action required");
         println!("{message}");
         println!("Continue when the action is performed.");
@@ -31,20 +31,20 @@ macro_rules! unknown {
     }};
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PieceEnum {
     Bulk(BulkPieceEnum),
     NonBulk(NonBulkPieceEnum),
 }
 
 #[non_exhaustive]
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum BulkPieceEnum {
     Apt(Apt),
 }
 
 #[non_exhaustive]
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum NonBulkPieceEnum {
     Command(Command),
     File(File),
@@ -67,9 +67,33 @@ impl NonBulkPieceEnum {
             Self::Manual(manual) => manual.undo(execution_data),
         }
     }
+
+    fn is_satisfied(&self, execution_data: &ExecutionData) -> Result<bool> {
+        match self {
+            Self::Command(command) => command.is_satisfied(execution_data),
+            Self::File(file) => file.is_satisfied(execution_data),
+            Self::Manual(manual) => manual.is_satisfied(execution_data),
+        }
+    }
+}
+
+impl BulkPieceEnum {
+    fn is_satisfied(&self, execution_data: &ExecutionData) -> Result<bool> {
+        match self {
+            Self::Apt(apt) => apt.is_satisfied(execution_data),
+        }
+    }
 }
 
 impl PieceEnum {
+    /// Check if the piece is already satisfied on this machine, so executing it can be skipped
+    pub fn is_satisfied(&self, execution_data: &ExecutionData) -> Result<bool> {
+        match self {
+            Self::Bulk(piece) => piece.is_satisfied(execution_data),
+            Self::NonBulk(piece) => piece.is_satisfied(execution_data),
+        }
+    }
+
     // TODO(low): maybe deduplicate between execute and undo with some generics or something?
     // TODO(low): Improve naming
     /// Execute multiple pieces
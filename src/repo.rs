@@ -1,22 +1,55 @@
+use crate::config::{Config, REPO_CONFIG_FILE};
 use crate::data::Data;
-use crate::machine::{Machine, MachineData};
+use crate::diff::FileDiff;
+use crate::pieces::file::IGNORE_FILE;
 use crate::utils::remove_empty_dirs;
 use auth_git2::GitAuthenticator;
 use color_eyre::Result;
 use color_eyre::eyre::{OptionExt as _, WrapErr as _, eyre};
-use git2::{Diff, Error, Repository, Status};
+use git2::build::RepoBuilder;
+use git2::{
+    Diff, DiffOptions, Error, ErrorCode, FetchOptions, IndexEntry, IndexTime, Oid, PushOptions,
+    RemoteCallbacks, Repository, Signature, Status, Tree,
+};
 use itertools::Itertools as _;
-use log::debug;
+use log::{debug, info};
 use std::fmt::{Debug, Formatter};
-use std::fs::{File, create_dir};
+use std::fs;
+use std::fs::{File, create_dir_all};
 use std::path::{Path, PathBuf};
 
-const BRANCH: &str = "main";
+/// How many times to try pushing (and merging when the push is rejected)
+const PUSH_ATTEMPTS: usize = 3;
+
+/// The files in the root of the repo that `push` commits, besides the files in the file dir
+pub const ROOT_FILES: [&str; 2] = [REPO_CONFIG_FILE, IGNORE_FILE];
+
+/// The changes that are committed along with the data file
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// Files relative to the file dir, committed as they are in the workdir
+    pub files: Vec<PathBuf>,
+    /// Files relative to the file dir, committed with this content instead. The rest of their
+    /// changes stay uncommitted in the workdir.
+    pub partial: Vec<(PathBuf, Vec<u8>)>,
+    /// Files in the root of the repo, see `ROOT_FILES`
+    pub root: Vec<String>,
+    /// Replaces the generated commit message
+    pub message: Option<String>,
+}
 
 pub struct Repo {
     repository: Repository,
     auth: GitAuthenticator,
     data: Data,
+    /// If true, the local repository and data file are never changed
+    dry_run: bool,
+    /// The branch that is pulled and pushed
+    branch: String,
+    /// The name and email address to commit with
+    identity: (String, String),
+    /// The directory tracked files are kept in, relative to the workdir
+    file_dir: String,
 }
 
 impl Debug for Repo {
@@ -36,32 +69,49 @@ impl Debug for Repo {
 //  This should also remove duplication of repo.pull_and_read and repo.write_and_push from cli functions
 
 impl Repo {
+    /// Clone the repo, or initialize a new one if `new`.
+    /// Returns the files that still need to be committed; this is done after registering the machine.
     pub fn init(
         remote: &str,
         path: &Path,
-        machine: Machine,
-        machine_data: MachineData,
         new: bool,
-    ) -> Result<()> {
+        config: &Config,
+    ) -> Result<(Self, Vec<PathBuf>)> {
         let auth = GitAuthenticator::default();
         debug!("Cloning repo");
-        let repository = auth
-            .clone_repo(remote, path)
+        let git_config = git2::Config::open_default().wrap_err("Failed to get config")?;
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(auth.credentials(&git_config));
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(callbacks);
+        let mut builder = RepoBuilder::new();
+        builder.fetch_options(fetch_options);
+        // A new repo is empty, so there is no branch to check out yet
+        if !new {
+            builder.branch(&config.branch);
+        }
+        let repository = builder
+            .clone(remote, path)
             .wrap_err("Failed to clone repository")?;
+        drop(builder);
 
         let mut files = vec![];
 
-        let mut repo = if new {
+        let repo = if new {
             debug!("New, so initializing new");
             let data = Data::init_new();
             let repo = Self {
                 repository,
                 auth,
                 data,
+                dry_run: false,
+                branch: config.branch.clone(),
+                identity: (config.commit_name.clone(), config.commit_email.clone()),
+                file_dir: config.file_dir.clone(),
             };
 
             let file_dir = repo.file_dir().wrap_err("Failed to get file dir")?;
-            create_dir(&file_dir).wrap_err("Failed to create file dir")?;
+            create_dir_all(&file_dir).wrap_err("Failed to create file dir")?;
             let gitkeep = file_dir.join(".gitkeep");
             files.push(".gitkeep".into());
             File::create(gitkeep).wrap_err("Failed to create .gitkeep")?;
@@ -76,22 +126,10 @@ impl Repo {
                     "This is not a falconf repo. Maybe you forgot `--new`? ({data_path:?} does not exist)"
                 ));
             }
-            Self::from_repository(repository).wrap_err("Failed to construct repo")?
+            Self::from_repository(repository, false, config).wrap_err("Failed to construct repo")?
         };
 
-        let mut config = repo.repository.config().wrap_err("Failed to get config")?;
-        config
-            .set_str("user.name", "falconf")
-            .wrap_err("Failed to set user.name")?;
-        config
-            .set_str("user.email", "falconf@example.com")
-            .wrap_err("Failed to set user.email")?;
-
-        let data = repo.data_mut();
-        data.machines_mut().insert(machine, machine_data);
-        repo.write_and_push(files)
-            .wrap_err("Failed to write_and_push")?;
-        Ok(())
+        Ok((repo, files))
     }
 
     pub fn workdir(&self) -> Result<&Path> {
@@ -99,7 +137,7 @@ impl Repo {
     }
 
     pub fn file_dir(&self) -> Result<PathBuf> {
-        Ok(self.workdir()?.join("files"))
+        Ok(self.workdir()?.join(&self.file_dir))
     }
 
     pub const fn data(&self) -> &Data {
@@ -110,9 +148,9 @@ impl Repo {
         &mut self.data
     }
 
-    pub fn get_from_path(path: &Path) -> Result<Self> {
+    pub fn get_from_path(path: &Path, dry_run: bool, config: &Config) -> Result<Self> {
         let repository = Repository::open(path).wrap_err("Failed to open repository")?;
-        Self::from_repository(repository)
+        Self::from_repository(repository, dry_run, config)
     }
 
     fn get_data(repository: &Repository) -> Result<Data> {
@@ -124,7 +162,23 @@ impl Repo {
         Ok(())
     }
 
-    fn from_repository(repository: Repository) -> Result<Self> {
+    /// Read the data file as it is in a commit, without checking it out
+    fn get_data_at(repository: &Repository, oid: Oid) -> Result<Data> {
+        let blob = repository
+            .find_commit(oid)
+            .wrap_err("Failed to find commit")?
+            .tree()
+            .wrap_err("Failed to get tree")?
+            .get_path(DATA_PATH.as_ref())
+            .wrap_err("Failed to find data file in commit")?
+            .to_object(repository)
+            .wrap_err("Failed to get data file object")?
+            .peel_to_blob()
+            .wrap_err("Failed to peel data file to blob")?;
+        Data::from_bytes(blob.content())
+    }
+
+    fn from_repository(repository: Repository, dry_run: bool, config: &Config) -> Result<Self> {
         let auth = GitAuthenticator::default();
         let data = Self::get_data(&repository).wrap_err("Failed to get data")?;
 
@@ -132,6 +186,10 @@ impl Repo {
             repository,
             auth,
             data,
+            dry_run,
+            branch: config.branch.clone(),
+            identity: (config.commit_name.clone(), config.commit_email.clone()),
+            file_dir: config.file_dir.clone(),
         };
         // This runs at the start of every run, so we do sanity checks here
         if repo.data_changed()? {
@@ -141,40 +199,101 @@ impl Repo {
         Ok(repo)
     }
 
-    fn pull(&self) -> Result<()> {
+    /// Fetch the remote branch, and return the fetched commit
+    fn fetch(&self) -> Result<Oid> {
         let mut remote = self
             .repository
             .find_remote("origin")
             .wrap_err("Failed to find remote")?;
         self.auth
-            .fetch(&self.repository, &mut remote, &[BRANCH], None)
+            .fetch(&self.repository, &mut remote, &[&self.branch], None)
             .wrap_err("Failed to fetch")?;
+        self.fetch_head()
+    }
 
+    /// The commit that was fetched last
+    fn fetch_head(&self) -> Result<Oid> {
         let fetch_head = self
             .repository
             .find_reference("FETCH_HEAD")
             .wrap_err("Failed to find fetch head")?;
-        let fetch_commit = self
+        Ok(fetch_head
+            .peel_to_commit()
+            .wrap_err("Failed to peel fetch head to commit")?
+            .id())
+    }
+
+    /// The data on the remote branch as it was fetched last, without merging it
+    pub fn fetched_data(&self) -> Result<Data> {
+        Self::get_data_at(&self.repository, self.fetch_head()?)
+    }
+
+    /// Fetch the remote branch, and return how many commits the local branch is ahead of and behind it
+    pub fn ahead_behind(&self) -> Result<(usize, usize)> {
+        let fetch_commit = self.fetch()?;
+        let head = self
             .repository
-            .reference_to_annotated_commit(&fetch_head)
-            .wrap_err("Failed to convert fetch head to annotated commit")?;
+            .head()
+            .wrap_err("Failed to get head")?
+            .peel_to_commit()
+            .wrap_err("Failed to peel head to commit")?;
+        self.repository
+            .graph_ahead_behind(head.id(), fetch_commit)
+            .wrap_err("Failed to compare local and remote branch")
+    }
+
+    /// The commit that is checked out, if there is one
+    pub fn head_commit(&self) -> Option<Oid> {
+        self.repository.head().ok()?.target()
+    }
+
+    pub fn remote_branch(&self) -> String {
+        format!("origin/{}", self.branch)
+    }
+
+    fn pull(&mut self) -> Result<()> {
+        let fetch_commit = self.fetch()?;
         let (analysis, _preference) = self
             .repository
-            .merge_analysis(&[&fetch_commit])
+            .merge_analysis(&[&self
+                .repository
+                .find_annotated_commit(fetch_commit)
+                .wrap_err("Failed to find fetched commit")?])
             .wrap_err("Failed to do merge analysis")?;
 
         if analysis.is_up_to_date() {
             Ok(())
         } else if analysis.is_fast_forward() {
-            let refname = format!("refs/heads/{BRANCH}");
+            if self.dry_run {
+                info!("Dry run! Reading the remote changes without fast-forwarding the local repo");
+                self.data = Self::get_data_at(&self.repository, fetch_commit)
+                    .wrap_err("Failed to get data from fetched commit")?;
+                return Ok(());
+            }
+            // Checked out before moving HEAD, as a safe checkout only updates what changed since
+            // HEAD. Not forced, so changes that weren't pushed are kept (or the checkout fails if
+            // they conflict).
+            self.checkout(
+                &self
+                    .repository
+                    .find_commit(fetch_commit)
+                    .wrap_err("Failed to find fetched commit")?
+                    .tree()
+                    .wrap_err("Failed to get tree of fetched commit")?,
+            )
+            .wrap_err("Failed to checkout fetched commit")?;
+            let refname = format!("refs/heads/{}", self.branch);
             let mut reference = self.repository.find_reference(&refname)?;
-            reference.set_target(fetch_commit.id(), "Fast-Forward")?;
+            reference.set_target(fetch_commit, "Fast-Forward")?;
             self.repository.set_head(&refname)?;
-            self.repository
-                .checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
             Ok(())
         } else {
-            Err(eyre!("Branches have diverted"))
+            info!("Both this machine and the remote have new changes; merging them");
+            self.merge(fetch_commit).wrap_err("Failed to merge")?;
+            if !self.dry_run {
+                self.push_and_merge()?;
+            }
+            Ok(())
         }
     }
 
@@ -192,36 +311,67 @@ impl Repo {
             .contains(Status::WT_MODIFIED))
     }
 
-    /// `files`: A list of files relative to the file dir that will be committed along with the data file.
-    fn commit(&self, files: Vec<PathBuf>) -> Result<()> {
-        let mut index = self.repository.index().wrap_err("Failed to get index")?;
+    /// Returns true if writing the data would change the data file
+    pub fn data_would_change(&self) -> Result<bool> {
+        let current = fs::read(data_path_from_repository(&self.repository)?)
+            .wrap_err("Failed to read data file")?;
+        Ok(current != self.data.to_ron()?.into_bytes())
+    }
 
+    /// The file dir relative to the repository workdir
+    fn file_dir_in_repository(&self) -> Result<PathBuf> {
         let file_dir = self.file_dir()?;
         #[expect(clippy::missing_panics_doc, reason = "see expect")]
-        let file_dir = file_dir
+        Ok(file_dir
             .strip_prefix(self.workdir()?)
-            .expect("File dir is always within repository workdir");
-        let mut files = files
+            .expect("File dir is always within repository workdir")
+            .to_path_buf())
+    }
+
+    /// Convert a list of files relative to the file dir to paths relative to the repository workdir
+    fn paths_in_repository(&self, files: Vec<PathBuf>) -> Result<Vec<String>> {
+        let file_dir = self.file_dir_in_repository()?;
+        Ok(files
             .into_iter()
             .map(|p| file_dir.join(p).to_string_lossy().to_string())
-            .collect::<Vec<_>>();
+            .collect())
+    }
+
+    fn commit(&self, changes: Changes) -> Result<()> {
+        let mut index = self.repository.index().wrap_err("Failed to get index")?;
+
+        let mut files = self.paths_in_repository(changes.files)?;
         files.push(DATA_PATH.to_owned());
+        files.extend(changes.root);
         index
             .add_all(&files, git2::IndexAddOption::DEFAULT, None)
             .wrap_err("Failed to add all")?;
+        let (partial_files, contents): (Vec<_>, Vec<_>) = changes.partial.into_iter().unzip();
+        for (path, content) in self
+            .paths_in_repository(partial_files)?
+            .into_iter()
+            .zip(contents)
+        {
+            let entry = index
+                .get_path(path.as_ref(), 0)
+                .unwrap_or_else(|| new_index_entry(&path, 0o100_644, 0));
+            index
+                .add_frombuffer(&entry, &content)
+                .wrap_err_with(|| format!("Failed to add part of {path}"))?;
+            files.push(path);
+        }
         index.write().wrap_err("Failed to write index")?;
 
         let oid = index.write_tree().wrap_err("Failed to write tree")?;
-        let signature = self
-            .repository
-            .signature()
-            .wrap_err("Failed to get signature")?;
+        let signature = self.signature()?;
         let tree = self
             .repository
             .find_tree(oid)
             .wrap_err("Failed to find tree")?;
 
-        let message = format!("falconf: Update {}", files.iter().join(", "));
+        let message = changes
+            .message
+            .unwrap_or_else(|| format!("falconf: Update {}", files.iter().join(", ")));
 
         if self.repository.head().is_ok() {
             debug!("Head exists");
@@ -245,10 +395,11 @@ impl Repo {
         } else {
             debug!("Head doesn't exist, this is the initial commit");
 
-            // Because there are no commits we need to make sure we're on `main` and not `master`
+            // Because there are no commits we need to make sure we're on the configured branch
+            //  and not `master`
             self.repository
-                .set_head("refs/heads/main")
-                .wrap_err("Failed to set head to main")?;
+                .set_head(&format!("refs/heads/{}", self.branch))
+                .wrap_err("Failed to set head to the branch")?;
 
             let parents = &[];
             self.repository.commit(
@@ -264,50 +415,347 @@ impl Repo {
         Ok(())
     }
 
-    fn push(&self) -> Result<()> {
-        // TODO(high):
-        //  I need to test what happens right now if you forget to `falconf push` and do a pull.
-        //  check for failed push
-        //  > Note that you’ll likely want to use RemoteCallbacks and set push_update_reference
-        //  > to test whether all the references were pushed successfully.
-        //  And return a divergence error when it fails because of divergence in the remote
+    fn signature(&self) -> Result<Signature<'static>> {
+        let (name, email) = &self.identity;
+        Signature::now(name, email).wrap_err("Failed to create signature")
+    }
+
+    /// Returns false if the push was rejected because the remote has changes we don't have
+    fn push(&self) -> Result<bool> {
         let mut remote = self
             .repository
             .find_remote("origin")
             .wrap_err("Failed to find remote")?;
-        self.auth
-            .push(
-                &self.repository,
-                &mut remote,
-                &[&format!("refs/heads/{BRANCH}")],
+        let git_config = self.repository.config().wrap_err("Failed to get config")?;
+
+        let mut rejection = None;
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(self.auth.credentials(&git_config));
+        // The remote can reject the update even if we think it's a fast-forward (e.g. when
+        //  someone else pushed in the meantime), we only find out about that here.
+        callbacks.push_update_reference(|refname, status| {
+            if let Some(status) = status {
+                rejection = Some(format!("{refname}: {status}"));
+            }
+            Ok(())
+        });
+        let mut push_options = PushOptions::new();
+        push_options.remote_callbacks(callbacks);
+
+        match remote.push(
+            &[&format!("refs/heads/{}", self.branch)],
+            Some(&mut push_options),
+        ) {
+            Ok(()) => {}
+            // libgit2 checks if the push is a fast-forward itself before pushing
+            Err(err) if err.code() == ErrorCode::NotFastForward => {
+                debug!("Push is not a fast-forward: {err}");
+                return Ok(false);
+            }
+            Err(err) => return Err(err).wrap_err("Failed to push"),
+        }
+        drop(push_options);
+
+        if let Some(rejection) = rejection {
+            debug!("Remote rejected the push: {rejection}");
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Push, and if the push is rejected, merge the remote changes and try again
+    fn push_and_merge(&mut self) -> Result<()> {
+        for _ in 0..PUSH_ATTEMPTS {
+            if self.push()? {
+                return Ok(());
+            }
+            info!("The remote has changes that are not present locally; merging them");
+            let fetch_commit = self.fetch()?;
+            self.merge(fetch_commit).wrap_err("Failed to merge")?;
+        }
+        Err(eyre!(
+            "The push was rejected {PUSH_ATTEMPTS} times, even after merging the remote changes"
+        ))
+    }
+
+    /// Merge a fetched commit into the local branch. The data file is merged semantically
+    /// (see `Data::merge`), any other conflict is an error.
+    fn merge(&mut self, fetch_commit: Oid) -> Result<()> {
+        let ours = self
+            .repository
+            .head()
+            .wrap_err("Failed to get head")?
+            .peel_to_commit()
+            .wrap_err("Failed to peel head to commit")?;
+        let theirs = self
+            .repository
+            .find_commit(fetch_commit)
+            .wrap_err("Failed to find fetched commit")?;
+        let base = self
+            .repository
+            .merge_base(ours.id(), theirs.id())
+            .wrap_err("Failed to find merge base")?;
+
+        let data = Data::merge(
+            &Self::get_data_at(&self.repository, base)?,
+            Self::get_data_at(&self.repository, ours.id())?,
+            Self::get_data_at(&self.repository, theirs.id())?,
+        )?;
+
+        if self.dry_run {
+            info!("Dry run! Merging the remote changes in memory only");
+            self.data = data;
+            return Ok(());
+        }
+
+        let mut index = self
+            .repository
+            .merge_commits(&ours, &theirs, None)
+            .wrap_err("Failed to merge commits")?;
+        for conflict in index.conflicts().wrap_err("Failed to get conflicts")? {
+            let conflict = conflict.wrap_err("Failed to get conflict")?;
+            let path = [conflict.our, conflict.their, conflict.ancestor]
+                .into_iter()
+                .flatten()
+                .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+                .next()
+                .unwrap_or_default();
+            if path != DATA_PATH {
+                return Err(eyre!(
+                    "Conflict: {path} was changed differently on this machine and on the remote"
+                ));
+            }
+        }
+        index
+            .conflict_remove(DATA_PATH.as_ref())
+            .wrap_err("Failed to remove data file conflict")?;
+        let content = data.to_ron()?;
+        let blob = self
+            .repository
+            .blob(content.as_bytes())
+            .wrap_err("Failed to write merged data file")?;
+        let mut entry = new_index_entry(
+            DATA_PATH,
+            0o100_644,
+            u32::try_from(content.len()).wrap_err("Merged data file is too large")?,
+        );
+        entry.id = blob;
+        index
+            .add(&entry)
+            .wrap_err("Failed to add merged data file to index")?;
+
+        let tree = self
+            .repository
+            .find_tree(
+                index
+                    .write_tree_to(&self.repository)
+                    .wrap_err("Failed to write tree")?,
             )
-            .wrap_err("Failed to push")?;
+            .wrap_err("Failed to find tree")?;
+        // Checked out before committing, as a safe checkout only updates what changed since HEAD.
+        // Not forced, so local changes to files are kept (or the checkout fails if they conflict).
+        self.checkout(&tree)
+            .wrap_err("Failed to checkout merge result")?;
+        let signature = self.signature()?;
+        self.repository
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                "falconf: Merge remote changes",
+                &tree,
+                &[&ours, &theirs],
+            )
+            .wrap_err("Failed to commit")?;
+
+        self.data = data;
+        Ok(())
+    }
+
+    /// Safely check out `tree`, keeping the permissions of the files it rewrites. A recreated file
+    /// gets the default permissions, which `push` would record as a permission change of the file
+    /// piece linking to it.
+    fn checkout(&self, tree: &Tree<'_>) -> Result<()> {
+        let head = self
+            .repository
+            .head()
+            .wrap_err("Failed to get head")?
+            .peel_to_tree()
+            .wrap_err("Failed to peel head to tree")?;
+        let workdir = self.workdir()?;
+        let permissions = self
+            .repository
+            .diff_tree_to_tree(Some(&head), Some(tree), None)
+            .wrap_err("Failed to diff trees")?
+            .deltas()
+            .filter_map(|delta| delta.old_file().path().map(|path| workdir.join(path)))
+            .filter_map(|path| Some((fs::metadata(&path).ok()?.permissions(), path)))
+            .collect::<Vec<_>>();
+
+        self.repository.checkout_tree(
+            tree.as_object(),
+            Some(git2::build::CheckoutBuilder::default().safe()),
+        )?;
+
+        for (permissions, path) in permissions {
+            if path.exists() {
+                fs::set_permissions(&path, permissions).wrap_err_with(|| {
+                    format!("Failed to restore permissions of {}", path.display())
+                })?;
+            }
+        }
         Ok(())
     }
 
     pub fn pull_and_read(&mut self) -> Result<()> {
         self.pull().wrap_err("Failed to pull")?;
-        self.update_data().wrap_err("Failed to update data")?;
+        // During a dry run the working tree isn't updated, `pull` reads the data itself
+        if !self.dry_run {
+            self.update_data().wrap_err("Failed to update data")?;
+        }
         Ok(())
     }
 
-    pub fn write_and_push(&self, files: Vec<PathBuf>) -> Result<()> {
+    pub fn write_and_push(&mut self, files: Vec<PathBuf>) -> Result<()> {
+        self.write_and_push_changes(Changes {
+            files,
+            ..Changes::default()
+        })
+    }
+
+    pub fn write_and_push_changes(&mut self, changes: Changes) -> Result<()> {
+        if self.dry_run {
+            return Err(eyre!(
+                "Refusing to write and push during a dry run. This shouldn't happen."
+            ));
+        }
         // If the data file changed or there are other files to commit
         self.write_data().wrap_err("Failed to write data")?;
-        if self.data_changed()? || !files.is_empty() {
-            // if args.dry_run {
-            //     return Err(eyre!(
-            //         "Somehow, the data file was changed during a dry run. This shouldn't happen."
-            //     ));
-            // }
-            self.commit(files).wrap_err("Failed to commit")?;
-            self.push().wrap_err("Failed to push")?;
+        if self.data_changed()?
+            || !changes.files.is_empty()
+            || !changes.partial.is_empty()
+            || !changes.root.is_empty()
+        {
+            self.commit(changes).wrap_err("Failed to commit")?;
+            self.push_and_merge().wrap_err("Failed to push")?;
+        }
+        Ok(())
+    }
+
+    /// Like `write_and_push`, but during a dry run only report what would be committed
+    pub fn write_and_push_unless_dry_run(&mut self, files: Vec<PathBuf>) -> Result<()> {
+        if !self.dry_run {
+            return self.write_and_push(files);
+        }
+
+        let mut files = self.paths_in_repository(files)?;
+        if self.data_would_change()? {
+            files.push(DATA_PATH.to_owned());
+        }
+        if files.is_empty() {
+            info!("Dry run! Nothing would be committed");
+        } else {
+            info!("Dry run! The following files would be committed and pushed:");
+            for file in files {
+                info!("- {file}");
+            }
         }
         Ok(())
     }
 
     pub fn diff_index_to_workdir(&self) -> std::result::Result<Diff<'_>, Error> {
-        self.repository.diff_index_to_workdir(None, None)
+        // New files in tracked directories are untracked until they're committed
+        let mut options = DiffOptions::new();
+        options
+            .include_untracked(true)
+            .recurse_untracked_dirs(true)
+            .show_untracked_content(true);
+        self.repository
+            .diff_index_to_workdir(None, Some(&mut options))
+    }
+
+    /// The changes to `files` (relative to the file dir) in the workdir, named by their location
+    pub fn file_diffs(&self, files: &[PathBuf]) -> Result<Vec<FileDiff>> {
+        let file_dir = self.file_dir()?;
+        files
+            .iter()
+            .map(|file| {
+                Ok(FileDiff {
+                    path: Path::new("/").join(file),
+                    old: self.indexed_content(file)?,
+                    new: fs::read(file_dir.join(file)).ok(),
+                })
+            })
+            .collect()
+    }
+
+    /// The changes to `files` in the root of the repo (see `ROOT_FILES`) in the workdir
+    pub fn root_file_diffs(&self, files: &[String]) -> Result<Vec<FileDiff>> {
+        let workdir = self.workdir()?;
+        files
+            .iter()
+            .map(|file| {
+                Ok(FileDiff {
+                    path: PathBuf::from(file),
+                    old: self.indexed_content_at(file)?,
+                    new: fs::read(workdir.join(file)).ok(),
+                })
+            })
+            .collect()
+    }
+
+    /// Return the paths with uncommitted changes, relative to the repository workdir
+    fn changed_paths(&self) -> Result<Vec<PathBuf>> {
+        Ok(self
+            .diff_index_to_workdir()?
+            .deltas()
+            .filter_map(|d| d.new_file().path().map(Path::to_path_buf))
+            .collect())
+    }
+
+    /// Return the files with uncommitted changes, relative to the file dir.
+    /// Files outside the file dir (like the global `.falconfignore`) are left out.
+    pub fn changed_files(&self) -> Result<Vec<PathBuf>> {
+        let file_dir = self.file_dir_in_repository()?;
+        Ok(self
+            .changed_paths()?
+            .iter()
+            .filter_map(|path| path.strip_prefix(&file_dir).ok())
+            .map(Path::to_path_buf)
+            .collect())
+    }
+
+    /// Return the files in the root of the repo (see `ROOT_FILES`) with uncommitted changes
+    pub fn changed_root_files(&self) -> Result<Vec<String>> {
+        let changed = self.changed_paths()?;
+        Ok(ROOT_FILES
+            .into_iter()
+            .filter(|file| changed.iter().any(|path| path == Path::new(file)))
+            .map(String::from)
+            .collect())
+    }
+
+    /// The content of a file (relative to the file dir) as it will be committed, if it's tracked
+    pub fn indexed_content(&self, file: &Path) -> Result<Option<Vec<u8>>> {
+        let path = self
+            .paths_in_repository(vec![file.to_path_buf()])?
+            .pop()
+            .ok_or_eyre("Unreachable: one path in, one path out")?;
+        self.indexed_content_at(&path)
+    }
+
+    /// Like `indexed_content`, for a path relative to the repository workdir
+    fn indexed_content_at(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        let index = self.repository.index().wrap_err("Failed to get index")?;
+        index
+            .get_path(path.as_ref(), 0)
+            .map(|entry| {
+                self.repository
+                    .find_blob(entry.id)
+                    .map(|blob| blob.content().to_vec())
+                    .wrap_err_with(|| format!("Failed to find blob of {path}"))
+            })
+            .transpose()
     }
 
     pub fn clean_file_dir(&self) -> Result<()> {
@@ -319,6 +767,24 @@ impl Repo {
 
 const DATA_PATH: &str = "data.ron";
 
+/// An index entry without stat information, to fill with `id` or `Index::add_frombuffer`
+fn new_index_entry(path: &str, mode: u32, file_size: u32) -> IndexEntry {
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode,
+        uid: 0,
+        gid: 0,
+        file_size,
+        id: Oid::ZERO_SHA1,
+        flags: 0,
+        flags_extended: 0,
+        path: path.into(),
+    }
+}
+
 fn workdir_from_repository(repo: &Repository) -> Result<&Path> {
     repo.workdir().ok_or_eyre("Repository is bare")
 }
@@ -331,8 +797,14 @@ fn data_path_from_repository(repo: &Repository) -> Result<PathBuf> {
 mod tests {
     use super::*;
     use crate::cli::TopLevelArgs;
+    use crate::cli::add::Piece;
+    use crate::cli::add::tests::{add_args_util, add_util, add_util_no_test_run};
     use crate::cli::init::tests::init_util;
+    use crate::diff::render;
+    use crate::full_piece::FullPiece;
     use crate::installation::Installation;
+    use crate::pieces::PieceEnum;
+    use crate::pieces::file::IGNORE_FILE;
     use crate::testing::TestRemote;
     use std::fs::OpenOptions;
     use std::io::Write;
@@ -355,4 +827,305 @@ mod tests {
 
         Ok(())
     }
+
+    fn command_piece(command: &str) -> Result<FullPiece> {
+        let args = add_args_util(Some(Piece::Command), vec![command.to_owned()], None);
+        Ok(FullPiece::new(PieceEnum::from_cli(&args)?, None))
+    }
+
+    #[test]
+    fn test_merge_concurrent_changes() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let local_1 = init_util(&remote, true)?;
+        let local_2 = init_util(&remote, false)?;
+
+        // Local 2 reads the data before local 1 pushes a new piece
+        let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), true);
+        let mut installation_2 = Installation::get(&top_level_args)?;
+        add_util(local_1.path(), Piece::Command, vec![String::from("true")])?;
+
+        // The push is rejected, so the remote changes are merged in and it's pushed again
+        installation_2
+            .repo_mut()
+            .data_mut()
+            .pieces_mut()
+            .insert(1, command_piece("false")?);
+        installation_2.repo_mut().write_and_push(vec![])?;
+
+        let top_level_args = TopLevelArgs::new_testing(local_1.path().clone(), true);
+        let mut installation_1 = Installation::get(&top_level_args)?;
+        installation_1.pull_and_read(false)?;
+        let pieces = installation_1.repo().data().pieces();
+        assert_eq!(pieces.len(), 2);
+        assert!(pieces.contains_key(&1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_removed_machine() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let local_1 = init_util(&remote, true)?;
+        add_util(local_1.path(), Piece::Command, vec![String::from("true")])?;
+        let local_2 = init_util(&remote, false)?;
+
+        let top_level_args = TopLevelArgs::new_testing(local_1.path().clone(), true);
+        let mut installation_1 = Installation::get(&top_level_args)?;
+        let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), true);
+        let mut installation_2 = Installation::get(&top_level_args)?;
+
+        // Local 1 is reset, while local 2 concurrently adds a piece
+        let machine_1 = *installation_1.machine();
+        installation_1
+            .repo_mut()
+            .data_mut()
+            .reset_machine(&machine_1);
+        installation_1.repo_mut().write_and_push(vec![])?;
+        installation_2
+            .repo_mut()
+            .data_mut()
+            .pieces_mut()
+            .insert(1, command_piece("false")?);
+        installation_2.repo_mut().write_and_push(vec![])?;
+
+        // The merge keeps the reset
+        installation_1.pull_and_read(false)?;
+        let pieces = installation_1.repo().data().pieces();
+        assert_eq!(pieces.len(), 2);
+        assert!(
+            !pieces
+                .get_index(0)
+                .ok_or_eyre("Cannot find added piece")?
+                .1
+                .done_on()
+                .contains(&machine_1)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_conflict() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let local_1 = init_util(&remote, true)?;
+        add_util(local_1.path(), Piece::Command, vec![String::from("true")])?;
+        let local_2 = init_util(&remote, false)?;
+
+        let top_level_args = TopLevelArgs::new_testing(local_1.path().clone(), true);
+        let mut installation_1 = Installation::get(&top_level_args)?;
+        let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), true);
+        let mut installation_2 = Installation::get(&top_level_args)?;
+
+        // Both machines change the comment of the same piece
+        for (installation, comment) in [(&mut installation_1, "one"), (&mut installation_2, "two")]
+        {
+            let repo = installation.repo_mut();
+            repo.data_mut()
+                .pieces_mut()
+                .get_index_mut(0)
+                .ok_or_eyre("Cannot find added piece")?
+                .1
+                .comment = Some(comment.to_owned());
+        }
+        installation_1.repo_mut().write_and_push(vec![])?;
+        let err = installation_2
+            .repo_mut()
+            .write_and_push(vec![])
+            .unwrap_err();
+        assert!(format!("{err:?}").contains("Conflict: the comment of piece"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_and_push_refuses_dry_run() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let local = init_util(&remote, true)?;
+        let top_level_args = TopLevelArgs::new_testing_dry_run(local.path().clone());
+        let mut installation = Installation::get(&top_level_args)?;
+        assert!(installation.repo_mut().write_and_push(vec![]).is_err());
+        assert!(
+            installation
+                .repo_mut()
+                .write_and_push_unless_dry_run(vec![])
+                .is_ok()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_diffs() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let local = init_util(&remote, true)?;
+        let temp = tempfile::TempDir::new()?;
+        let text = temp.path().join("text");
+        let binary = temp.path().join("binary");
+        fs::write(&text, "line\n")?;
+        fs::write(&binary, [0, 159, 146, 150])?;
+        for file in [&text, &binary] {
+            add_util_no_test_run(local.path(), Piece::File, vec![file.display().to_string()])?;
+        }
+
+        fs::write(&text, "changed line\n")?;
+        fs::write(&binary, [0, 159, 146, 150, 0])?;
+        let top_level_args = TopLevelArgs::new_testing(local.path().clone(), false);
+        let installation = Installation::get(&top_level_args)?;
+        let mut diff = vec![];
+        let repo = installation.repo();
+        render(&mut diff, &repo.file_diffs(&repo.changed_files()?)?, false)?;
+        let diff = String::from_utf8(diff)?;
+        assert!(diff.contains(&format!(
+            "--- a{0}\n+++ b{0}\n@@ -1 +1 @@\n-line\n+changed line\n",
+            text.display()
+        )));
+        assert!(diff.contains(&format!(
+            "Binary file changed: 4 bytes (md5 {:x}) -> 5 bytes (md5 {:x})\n",
+            md5::compute([0, 159, 146, 150]),
+            md5::compute([0, 159, 146, 150, 0])
+        )));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_commit() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let local_1 = init_util(&remote, true)?;
+        let local_2 = init_util(&remote, false)?;
+        let temp = tempfile::TempDir::new()?;
+        let whole = temp.path().join("whole");
+        let partial = temp.path().join("partial");
+        let skipped = temp.path().join("skipped");
+        for file in [&whole, &partial, &skipped] {
+            fs::write(file, "line\n")?;
+            add_util_no_test_run(
+                local_1.path(),
+                Piece::File,
+                vec![file.display().to_string()],
+            )?;
+        }
+        for file in [&whole, &partial, &skipped] {
+            fs::write(file, "line\nchanged\n")?;
+        }
+
+        let top_level_args = TopLevelArgs::new_testing(local_1.path().clone(), false);
+        let mut installation = Installation::get(&top_level_args)?;
+        let repo = installation.repo_mut();
+        let relative =
+            |path: &Path| -> Result<PathBuf> { Ok(path.strip_prefix("/")?.to_path_buf()) };
+        repo.write_and_push_changes(Changes {
+            files: vec![relative(&whole)?],
+            partial: vec![(relative(&partial)?, b"line\nhalf\n".to_vec())],
+            message: Some(String::from("Custom message")),
+            ..Changes::default()
+        })?;
+        assert_eq!(
+            repo.repository.head()?.peel_to_commit()?.message()?,
+            "Custom message"
+        );
+        assert_eq!(
+            repo.indexed_content(&relative(&partial)?)?,
+            Some(b"line\nhalf\n".to_vec())
+        );
+        assert_eq!(
+            repo.changed_files()?,
+            vec![relative(&partial)?, relative(&skipped)?]
+        );
+
+        // The uncommitted changes don't get in the way of pulling
+        add_util(local_2.path(), Piece::Command, vec![String::from("true")])?;
+        installation.pull_and_read(false)?;
+        assert_eq!(installation.repo().data().pieces().len(), 4);
+        assert_eq!(fs::read_to_string(&skipped)?, "line\nchanged\n");
+        assert_eq!(fs::read_to_string(&partial)?, "line\nchanged\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_configured_repo_settings() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let temp = tempfile::TempDir::new()?;
+        let config = Config {
+            branch: String::from("other"),
+            commit_name: String::from("Someone"),
+            file_dir: String::from("dotfiles/home"),
+            ..Config::default()
+        };
+        let (mut repo, files) =
+            Repo::init(remote.address(), &temp.path().join("1"), true, &config)?;
+        repo.write_and_push(files)?;
+
+        let (repo, _files) = Repo::init(remote.address(), &temp.path().join("2"), false, &config)?;
+        let head = repo.repository.head()?;
+        assert_eq!(head.shorthand()?, "other");
+        assert_eq!(head.peel_to_commit()?.author().name()?, "Someone");
+        assert!(
+            head.peel_to_tree()?
+                .get_path(Path::new("dotfiles/home/.gitkeep"))
+                .is_ok()
+        );
+        assert_eq!(repo.file_dir()?, repo.workdir()?.join("dotfiles/home"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_changed_files_outside_file_dir() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let local = init_util(&remote, true)?;
+        let temp = tempfile::TempDir::new()?;
+        let file = temp.path().join("file");
+        fs::write(&file, "content")?;
+        add_util_no_test_run(local.path(), Piece::File, vec![file.display().to_string()])?;
+
+        fs::write(&file, "changed")?;
+        let repository = Installation::get_repository_path(local.path());
+        fs::write(repository.join(IGNORE_FILE), "*.bak\n")?;
+        let top_level_args = TopLevelArgs::new_testing(local.path().clone(), false);
+        let installation = Installation::get(&top_level_args)?;
+        assert_eq!(
+            installation.repo().changed_files()?,
+            vec![file.strip_prefix("/")?.to_path_buf()]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_root_files() -> Result<()> {
+        let remote = TestRemote::new()?;
+        let local_1 = init_util(&remote, true)?;
+        let local_2 = init_util(&remote, false)?;
+        let config = "shell = \"zsh\"\n";
+        fs::write(
+            Installation::get_repository_path(local_1.path()).join(REPO_CONFIG_FILE),
+            config,
+        )?;
+
+        let top_level_args = TopLevelArgs::new_testing(local_1.path().clone(), false);
+        let mut installation = Installation::get(&top_level_args)?;
+        let repo = installation.repo_mut();
+        let root = repo.changed_root_files()?;
+        assert_eq!(root, vec![String::from(REPO_CONFIG_FILE)]);
+        assert!(repo.changed_files()?.is_empty());
+        repo.write_and_push_changes(Changes {
+            root,
+            ..Changes::default()
+        })?;
+        assert!(repo.changed_root_files()?.is_empty());
+
+        let top_level_args = TopLevelArgs::new_testing(local_2.path().clone(), false);
+        let mut installation = Installation::get(&top_level_args)?;
+        installation.pull_and_read(false)?;
+        assert_eq!(
+            fs::read_to_string(
+                Installation::get_repository_path(local_2.path()).join(REPO_CONFIG_FILE)
+            )?,
+            config
+        );
+
+        Ok(())
+    }
 }
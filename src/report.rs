@@ -0,0 +1,118 @@
+use crate::utils::print_id;
+use color_eyre::owo_colors::OwoColorize as _;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// The error a piece returns when it needs input but can't ask for it. The piece is left pending
+/// instead of failing the sync.
+#[derive(Debug)]
+pub struct Deferred(pub String);
+
+impl Display for Deferred {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Deferred: {}", self.0)
+    }
+}
+
+impl Error for Deferred {}
+
+/// What happened to the pieces during a sync, for `sync --keep-going` and deferred pieces
+#[derive(Debug, Default)]
+pub struct Report {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    id: u32,
+    /// The piece, as displayed
+    piece: String,
+    undo: bool,
+    outcome: Outcome,
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    Succeeded,
+    /// Marked as done without executing it, because it was already satisfied
+    Satisfied,
+    Failed(String),
+    /// Left pending because it needs input, see `Deferred`
+    Deferred(String),
+    /// Not executed because it depends on a piece that failed, was deferred, or was skipped
+    Skipped,
+}
+
+impl Report {
+    pub fn push(&mut self, id: u32, piece: String, undo: bool, outcome: Outcome) {
+        self.entries.push(Entry {
+            id,
+            piece,
+            undo,
+            outcome,
+        });
+    }
+
+    /// Returns true if the piece failed, was deferred, or was skipped, so pieces depending on it
+    /// should be skipped
+    pub fn blocks(&self, id: u32) -> bool {
+        self.entries.iter().any(|entry| {
+            entry.id == id
+                && matches!(
+                    entry.outcome,
+                    Outcome::Failed(_) | Outcome::Deferred(_) | Outcome::Skipped
+                )
+        })
+    }
+
+    pub fn failed(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| matches!(entry.outcome, Outcome::Failed(_)))
+            .count()
+    }
+
+    pub fn deferred(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| matches!(entry.outcome, Outcome::Deferred(_)))
+            .count()
+    }
+
+    #[expect(clippy::print_stdout)]
+    pub fn print(&self) {
+        let count = |f: fn(&Outcome) -> bool| {
+            self.entries
+                .iter()
+                .filter(|entry| f(&entry.outcome))
+                .count()
+        };
+
+        println!("Summary:");
+        for entry in &self.entries {
+            let outcome = match &entry.outcome {
+                Outcome::Succeeded => format!("{}", "Succeeded".green()),
+                Outcome::Satisfied => format!("{}", "Satisfied".green()),
+                Outcome::Failed(_) => format!("{}", "Failed   ".red()),
+                Outcome::Deferred(_) => format!("{}", "Deferred ".yellow()),
+                Outcome::Skipped => format!("{}", "Skipped  ".yellow()),
+            };
+            let action = if entry.undo { "Undo   " } else { "Execute" };
+            println!(
+                "  {outcome}  {action}  {} {}",
+                print_id(entry.id),
+                entry.piece
+            );
+            if let Outcome::Failed(reason) | Outcome::Deferred(reason) = &entry.outcome {
+                println!("      {reason}");
+            }
+        }
+        println!(
+            "{} succeeded, {} failed, {} deferred, {} skipped",
+            count(|outcome| matches!(outcome, Outcome::Succeeded | Outcome::Satisfied)),
+            count(|outcome| matches!(outcome, Outcome::Failed(_))),
+            count(|outcome| matches!(outcome, Outcome::Deferred(_))),
+            count(|outcome| matches!(outcome, Outcome::Skipped)),
+        );
+    }
+}
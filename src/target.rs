@@ -0,0 +1,44 @@
+use crate::cli::add;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// The machines a piece applies to, based on the tags of the machines
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Target {
+    /// If not empty, only machines with at least one of these tags are targeted
+    only: Vec<String>,
+    /// Machines with any of these tags are not targeted
+    except: Vec<String>,
+}
+
+impl Target {
+    pub fn matches(&self, tags: &[String]) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|tag| tags.contains(tag)))
+            && !self.except.iter().any(|tag| tags.contains(tag))
+    }
+
+    /// Returns `None` if the piece should target all machines
+    pub fn from_cli(args: &add::Args) -> Option<Self> {
+        if args.only.is_empty() && args.except.is_empty() {
+            None
+        } else {
+            Some(Self {
+                only: args.only.clone(),
+                except: args.except.clone(),
+            })
+        }
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if !self.only.is_empty() {
+            parts.push(format!("only: {}", self.only.join(", ")));
+        }
+        if !self.except.is_empty() {
+            parts.push(format!("except: {}", self.except.join(", ")));
+        }
+        write!(f, "{}", parts.join("; "))
+    }
+}
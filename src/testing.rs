@@ -107,7 +107,7 @@ impl TestRemote {
 
         set_current_dir(&local)?;
 
-        // This is normally handled by Repo::init but we do it manually here
+        // Repo commits with the configured identity, but git needs one set to commit here
         Command::new("git")
             .arg("config")
             .arg("user.email")
@@ -1,19 +1,23 @@
 use color_eyre::Result;
-use color_eyre::eyre::OptionExt as _;
+use color_eyre::eyre::{OptionExt as _, WrapErr as _};
 use color_eyre::owo_colors::OwoColorize as _;
-use std::io::Write as _;
+use log::info;
+use std::io::{Read as _, Write as _};
 use std::path::Path;
-use std::{fs, io};
-
-// pub fn if_sudo(program: &str, sudo: bool) -> process::Command {
-//     if sudo {
-//         let mut cmd = process::Command::new("sudo");
-//         cmd.arg(program);
-//         cmd
-//     } else {
-//         process::Command::new(program)
-//     }
-// }
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, io, process};
+
+/// Create a command that runs as root through the escalation command (like `sudo`) if `sudo`
+pub fn if_sudo(program: &str, sudo: bool, escalate: &[String]) -> process::Command {
+    match escalate.split_first() {
+        Some((escalate, args)) if sudo => {
+            let mut cmd = process::Command::new(escalate);
+            cmd.args(args).arg(program);
+            cmd
+        }
+        _ => process::Command::new(program),
+    }
+}
 
 #[expect(clippy::print_stdout)]
 pub fn press_enter() -> io::Result<()> {
@@ -42,6 +46,87 @@ pub fn confirm(question: &str) -> io::Result<bool> {
     }
 }
 
+/// Whether the user can be asked for input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    Interactive,
+    /// Answer confirmations with no, and defer what needs other input
+    No,
+    /// Answer confirmations with yes, and defer what needs other input
+    Yes,
+}
+
+impl Input {
+    pub fn is_interactive(self) -> bool {
+        self == Self::Interactive
+    }
+
+    /// Ask `question`, or answer it without asking
+    pub fn confirm(self, question: &str) -> io::Result<bool> {
+        match self {
+            Self::Interactive => confirm(question),
+            Self::No => {
+                info!("{question} Answering no without asking; use `--yes` to answer yes");
+                Ok(false)
+            }
+            Self::Yes => {
+                info!("{question} Answering yes without asking");
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Ask the user to choose one of `options`, by typing it or its first letter.
+/// Returns the index of the chosen option.
+#[expect(clippy::print_stdout)]
+pub fn choose(question: &str, options: &[&str]) -> io::Result<usize> {
+    loop {
+        print!("{question} ({}) ", options.join("/"));
+        io::stdout().flush()?;
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        let ans = input.to_ascii_lowercase();
+        let ans = ans.trim_end();
+        if let Some(index) = options
+            .iter()
+            .position(|option| *option == ans || option.get(..1) == Some(ans))
+        {
+            return Ok(index);
+        }
+        println!("Invalid answer.");
+    }
+}
+
+/// Describe a change to a binary file by its size and hash, as it can't be shown as a diff.
+/// `None` means the file didn't or doesn't exist.
+pub fn binary_summary(old: Option<&[u8]>, new: Option<&[u8]>) -> String {
+    let describe = |content: Option<&[u8]>| {
+        content.map_or_else(
+            || String::from("nothing"),
+            |content| format!("{} bytes (md5 {:x})", content.len(), md5::compute(content)),
+        )
+    };
+    format!(
+        "Binary file changed: {} -> {}\n",
+        describe(old),
+        describe(new)
+    )
+}
+
+/// Read the content of a file, or of stdin if the path is '-'
+pub fn read_file_or_stdin(path: &str) -> Result<Vec<u8>> {
+    if path == "-" {
+        let mut content = vec![];
+        io::stdin()
+            .read_to_end(&mut content)
+            .wrap_err("Failed to read stdin")?;
+        Ok(content)
+    } else {
+        fs::read(path).wrap_err_with(|| format!("Failed to read {path}"))
+    }
+}
+
 #[expect(clippy::print_stdout)]
 pub fn prompt(question: &str) -> io::Result<String> {
     print!("{question}");
@@ -55,6 +140,37 @@ pub fn set_eq<T: Eq>(vec1: &[T], vec2: &[T]) -> bool {
     vec1.len() == vec2.len() && vec1.iter().all(|x| vec2.contains(x))
 }
 
+/// Three-way merge of two vecs used as sets: items added on either side are kept, and items
+/// removed on either side are removed. Keeps the order of `ours` and appends new items from
+/// `theirs`.
+pub fn merge_set<T: Eq>(base: Option<&[T]>, ours: Vec<T>, theirs: Vec<T>) -> Vec<T> {
+    let base = base.unwrap_or_default();
+    let removed = |x: &T, side: &[T]| base.contains(x) && !side.contains(x);
+    let mut merged = vec![];
+    for x in ours {
+        if !removed(&x, &theirs) {
+            merged.push(x);
+        }
+    }
+    for x in theirs {
+        if !merged.contains(&x) && !base.contains(&x) {
+            merged.push(x);
+        }
+    }
+    merged
+}
+
+/// Three-way merge of a single value. Returns `None` if it was changed differently on both sides.
+pub fn merge_value<T: Eq>(base: Option<&T>, ours: T, theirs: T) -> Option<T> {
+    if ours == theirs || base == Some(&theirs) {
+        Some(ours)
+    } else if base == Some(&ours) {
+        Some(theirs)
+    } else {
+        None
+    }
+}
+
 pub fn create_parent(path: &Path) -> Result<()> {
     let parent = path.parent().ok_or_eyre("File doesn't have parent")?;
     if !parent.exists() {
@@ -81,6 +197,30 @@ pub fn remove_empty_dirs(path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Returns true if both files exist and have the same content
+pub fn same_content(a: &Path, b: &Path) -> bool {
+    match (fs::read(a), fs::read(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Format a timestamp (in seconds since the Unix epoch) relative to now, like "3 days ago"
+pub fn format_elapsed(timestamp: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let elapsed = now.saturating_sub(timestamp);
+    let (amount, unit) = match elapsed {
+        0..60 => return String::from("just now"),
+        60..3600 => (elapsed / 60, "minute"),
+        3600..86400 => (elapsed / 3600, "hour"),
+        _ => (elapsed / 86400, "day"),
+    };
+    let plural = if amount == 1 { "" } else { "s" };
+    format!("{amount} {unit}{plural} ago")
+}
+
 pub fn print_id(id: u32) -> String {
     let id = format!("[{id:08x}]");
     let id = id.magenta();